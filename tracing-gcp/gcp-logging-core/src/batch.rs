@@ -0,0 +1,163 @@
+//! A writer that coalesces entries into fewer, larger writes.
+
+use std::io::{self, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+#[derive(Default)]
+struct Batch {
+    lines: Vec<u8>,
+    entries: usize,
+    closed: bool,
+    write_failed: bool,
+}
+
+struct Shared {
+    batch: Mutex<Batch>,
+    ready: Condvar,
+    max_entries: usize,
+}
+
+/// Buffers the entries written to it and hands them to a background thread, which
+/// writes them to the inner writer with one `write_all` once `max_entries` entries are
+/// buffered or `max_delay` has passed, whichever comes first.
+///
+/// Clones share the same buffer. Keep the [`BatchGuard`] returned with the writer alive
+/// until shutdown: dropping it writes the entries still buffered.
+#[derive(Clone)]
+pub struct BatchWriter {
+    shared: Arc<Shared>,
+}
+
+/// Writes the entries still buffered by its [`BatchWriter`] when dropped.
+#[must_use = "dropping the guard stops writing entries"]
+pub struct BatchGuard {
+    shared: Arc<Shared>,
+    flusher: Option<JoinHandle<()>>,
+}
+
+impl BatchWriter {
+    pub fn new<W: Write + Send + 'static>(
+        mut inner: W,
+        max_entries: usize,
+        max_delay: Duration,
+    ) -> (BatchWriter, BatchGuard) {
+        let shared = Arc::new(Shared {
+            batch: Mutex::default(),
+            ready: Condvar::new(),
+            max_entries: max_entries.max(1),
+        });
+        let flusher = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let mut batch = shared.batch.lock().unwrap();
+                loop {
+                    if batch.entries < shared.max_entries && !batch.closed {
+                        batch = shared.ready.wait_timeout(batch, max_delay).unwrap().0;
+                    }
+                    let closed = batch.closed;
+                    let lines = std::mem::take(&mut batch.lines);
+                    batch.entries = 0;
+                    drop(batch);
+                    let written = lines.is_empty()
+                        || inner.write_all(&lines).and_then(|()| inner.flush()).is_ok();
+                    batch = shared.batch.lock().unwrap();
+                    batch.write_failed |= !written;
+                    if closed {
+                        return;
+                    }
+                }
+            })
+        };
+        let guard = BatchGuard { shared: shared.clone(), flusher: Some(flusher) };
+        (BatchWriter { shared }, guard)
+    }
+}
+
+impl Write for BatchWriter {
+    /// Expects a whole entry per call, as [`with_serialized_line`] writes them.
+    ///
+    /// [`with_serialized_line`]: crate::with_serialized_line
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut batch = self.shared.batch.lock().unwrap();
+        if batch.closed {
+            return Ok(buf.len());
+        }
+        batch.lines.extend_from_slice(buf);
+        batch.entries += 1;
+        if batch.entries >= self.shared.max_entries {
+            self.shared.ready.notify_one();
+        }
+        Ok(buf.len())
+    }
+
+    /// Wakes the background thread up to write the buffered entries, without waiting
+    /// for it.
+    fn flush(&mut self) -> io::Result<()> {
+        self.shared.ready.notify_one();
+        Ok(())
+    }
+}
+
+impl BatchGuard {
+    /// Writes the entries still buffered and waits for the background thread to stop.
+    /// Returns whether every batch was written successfully. Entries written to the
+    /// [`BatchWriter`] afterwards are dropped.
+    pub fn shutdown(mut self) -> bool {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> bool {
+        let Some(flusher) = self.flusher.take() else { return true };
+        self.shared.batch.lock().unwrap().closed = true;
+        self.shared.ready.notify_one();
+        let _ = flusher.join();
+        !self.shared.batch.lock().unwrap().write_failed
+    }
+}
+
+impl Drop for BatchGuard {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    /// Keeps each write separately.
+    #[derive(Clone, Default)]
+    struct Writes(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn writes_every_buffered_entry_after_the_delay() {
+        let writes = Writes::default();
+        let (mut writer, guard) =
+            BatchWriter::new(writes.clone(), 100, Duration::from_millis(50));
+        for line in [&b"{\"n\":1}\n"[..], b"{\"n\":2}\n", b"{\"n\":3}\n"] {
+            writer.write_all(line).unwrap();
+        }
+        let written = || writes.0.lock().unwrap().concat();
+        let start = Instant::now();
+        while written().len() < 24 {
+            assert!(start.elapsed() < Duration::from_secs(5), "{:?}", written());
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(written(), b"{\"n\":1}\n{\"n\":2}\n{\"n\":3}\n");
+        assert!(guard.shutdown());
+    }
+}
@@ -0,0 +1,55 @@
+//! Helpers for asserting on the entries a backend writes, without reading stderr.
+
+use crate::Clock;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+/// A writer that keeps every entry written to it as JSON. Clones share the same
+/// entries, so a test can hand one to the backend and read them back from another.
+#[derive(Clone, Debug, Default)]
+pub struct CaptureWriter {
+    entries: Arc<Mutex<Vec<Value>>>,
+}
+
+impl CaptureWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The entries written so far, oldest first.
+    pub fn entries(&self) -> Vec<Value> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    /// Removes and returns the entries written so far.
+    pub fn take(&self) -> Vec<Value> {
+        std::mem::take(&mut self.entries.lock().unwrap())
+    }
+}
+
+impl Write for CaptureWriter {
+    /// Expects a whole entry per call, as [`with_serialized_line`] writes them.
+    ///
+    /// [`with_serialized_line`]: crate::with_serialized_line
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let entry = serde_json::from_slice(buf).map_err(io::Error::other)?;
+        self.entries.lock().unwrap().push(entry);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A clock stopped at the given time, so that captured entries are deterministic.
+#[derive(Clone, Copy, Debug)]
+pub struct FixedClock(pub DateTime<Utc>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0
+    }
+}
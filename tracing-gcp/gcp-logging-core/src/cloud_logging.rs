@@ -0,0 +1,313 @@
+//! A writer that sends entries to the Cloud Logging API instead of stdout, for hosts
+//! without a logging agent.
+
+use crate::MonitoredResource;
+use chrono::DateTime;
+use serde_json::{Map, Value, json};
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+/// The `entries.write` method of the Cloud Logging REST API.
+pub const ENTRIES_WRITE_URL: &str = "https://logging.googleapis.com/v2/entries:write";
+
+/// Gets the OAuth access token `entries.write` requests are authorized with, e.g. from
+/// the metadata server or a service account key. Called before each request, so it
+/// should cache the token until it expires.
+pub trait TokenProvider: Send + Sync + 'static {
+    fn token(&self) -> impl Future<Output = io::Result<String>> + Send;
+}
+
+impl<F, Fut> TokenProvider for F
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = io::Result<String>> + Send,
+{
+    fn token(&self) -> impl Future<Output = io::Result<String>> + Send {
+        self()
+    }
+}
+
+/// Configures the writer and background task sending entries to Cloud Logging.
+///
+/// The client is a plain `reqwest::Client`: enable one of reqwest's TLS features
+/// (e.g. `rustls-tls`) in the application to reach the `https` endpoint.
+pub struct CloudLoggingBuilder<T> {
+    log_name: String,
+    tokens: T,
+    endpoint: String,
+    resource: MonitoredResource,
+    client: reqwest::Client,
+    max_entries: usize,
+    max_delay: Duration,
+    queue_capacity: usize,
+    max_retries: u32,
+}
+
+/// Queues the entries written to it for the background task started by
+/// [`CloudLoggingBuilder::spawn`], which sends them in batches.
+///
+/// Clones share the same queue. Entries written while the queue is full are dropped
+/// with a warning on stderr.
+#[derive(Clone)]
+pub struct CloudLoggingWriter {
+    queue: mpsc::Sender<Value>,
+    warned_full: Arc<AtomicBool>,
+}
+
+/// Stops the background task of a [`CloudLoggingWriter`].
+#[must_use = "dropping the guard stops sending entries"]
+pub struct CloudLoggingGuard {
+    stop: Option<oneshot::Sender<()>>,
+    sender: Option<JoinHandle<bool>>,
+}
+
+impl<T: TokenProvider> CloudLoggingBuilder<T> {
+    /// Sends entries to `log_name`, formatted by [`format_log_name`], unless they
+    /// set their own `logName`.
+    ///
+    /// [`format_log_name`]: crate::format_log_name
+    pub fn new(log_name: impl Into<String>, tokens: T) -> Self {
+        CloudLoggingBuilder {
+            log_name: log_name.into(),
+            tokens,
+            endpoint: ENTRIES_WRITE_URL.into(),
+            resource: MonitoredResource {
+                resource_type: "global".into(),
+                labels: Default::default(),
+            },
+            client: reqwest::Client::new(),
+            max_entries: 500,
+            max_delay: Duration::from_secs(1),
+            queue_capacity: 10_000,
+            max_retries: 5,
+        }
+    }
+
+    /// Posts to `endpoint` instead of [`ENTRIES_WRITE_URL`].
+    pub fn endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.endpoint = endpoint.into();
+        self
+    }
+
+    /// The resource of entries that don't set their own, `global` by default.
+    pub fn resource(mut self, resource: MonitoredResource) -> Self {
+        self.resource = resource;
+        self
+    }
+
+    pub fn client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Sends a batch once `max_entries` entries are queued or `max_delay` has passed
+    /// since the first of them, whichever comes first. Defaults to 500 entries and 1s.
+    pub fn batch(mut self, max_entries: usize, max_delay: Duration) -> Self {
+        self.max_entries = max_entries.max(1);
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// How many entries can wait to be sent before new ones are dropped, 10000 by
+    /// default.
+    pub fn queue_capacity(mut self, queue_capacity: usize) -> Self {
+        self.queue_capacity = queue_capacity.max(1);
+        self
+    }
+
+    /// How many times a batch is retried, with exponential backoff, when the API
+    /// answers 429 or 5xx or can't be reached. Defaults to 5.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Starts the background task on the current tokio runtime.
+    ///
+    /// Panics when called outside of a tokio runtime.
+    pub fn spawn(self) -> (CloudLoggingWriter, CloudLoggingGuard) {
+        let (queue, entries) = mpsc::channel(self.queue_capacity);
+        let (stop, stopped) = oneshot::channel();
+        let sender = tokio::spawn(self.run(entries, stopped));
+        let writer = CloudLoggingWriter { queue, warned_full: Arc::default() };
+        (writer, CloudLoggingGuard { stop: Some(stop), sender: Some(sender) })
+    }
+
+    async fn run(
+        self,
+        mut entries: mpsc::Receiver<Value>,
+        mut stopped: oneshot::Receiver<()>,
+    ) -> bool {
+        let mut all_sent = true;
+        loop {
+            let mut batch = Vec::new();
+            tokio::select! {
+                Some(entry) = entries.recv() => batch.push(entry),
+                _ = &mut stopped => {
+                    while let Ok(entry) = entries.try_recv() {
+                        batch.push(entry);
+                    }
+                    for batch in batch.chunks(self.max_entries) {
+                        all_sent &= self.send(batch).await;
+                    }
+                    return all_sent;
+                }
+            }
+            let deadline = tokio::time::Instant::now() + self.max_delay;
+            while batch.len() < self.max_entries {
+                match tokio::time::timeout_at(deadline, entries.recv()).await {
+                    Ok(Some(entry)) => batch.push(entry),
+                    Ok(None) | Err(_) => break,
+                }
+            }
+            all_sent &= self.send(&batch).await;
+        }
+    }
+
+    /// Posts `batch`, retrying it on 429, 5xx and connection errors. Returns whether
+    /// it was written.
+    async fn send(&self, batch: &[Value]) -> bool {
+        let body = json!({
+            "logName": self.log_name,
+            "resource": self.resource,
+            "entries": batch,
+        });
+        let body = serde_json::to_vec(&body).expect("entries serialize to JSON");
+        let mut backoff = Duration::from_millis(500);
+        for attempt in 0..=self.max_retries {
+            if attempt > 0 {
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(30));
+            }
+            let token = match self.tokens.token().await {
+                Ok(token) => token,
+                Err(e) => {
+                    eprintln!(
+                        "dropping {} log entries: no access token: {e}",
+                        batch.len()
+                    );
+                    return false;
+                }
+            };
+            let response = self
+                .client
+                .post(&self.endpoint)
+                .bearer_auth(token)
+                .header("Content-Type", "application/json")
+                .body(body.clone())
+                .send()
+                .await;
+            match response {
+                Ok(response) if response.status().is_success() => return true,
+                Ok(response)
+                    if response.status() != 429
+                        && !response.status().is_server_error() =>
+                {
+                    eprintln!(
+                        "dropping {} log entries: entries.write answered {}",
+                        batch.len(),
+                        response.status()
+                    );
+                    return false;
+                }
+                Ok(_) | Err(_) => {}
+            }
+        }
+        eprintln!(
+            "dropping {} log entries: entries.write failed {} times",
+            batch.len(),
+            self.max_retries + 1
+        );
+        false
+    }
+}
+
+impl Write for CloudLoggingWriter {
+    /// Expects a whole entry per call, as [`with_serialized_line`] writes them. Lines
+    /// that aren't JSON objects, such as text payloads, are sent as `textPayload`.
+    ///
+    /// [`with_serialized_line`]: crate::with_serialized_line
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let entry = to_api_entry(buf);
+        match self.queue.try_send(entry) {
+            Ok(()) => self.warned_full.store(false, Ordering::Relaxed),
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                if !self.warned_full.swap(true, Ordering::Relaxed) {
+                    eprintln!("dropping log entries: the Cloud Logging queue is full");
+                }
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {}
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl CloudLoggingGuard {
+    /// Sends the entries still queued and waits for the background task to stop.
+    /// Returns whether every batch was written. Entries written to the
+    /// [`CloudLoggingWriter`] afterwards are dropped.
+    pub async fn shutdown(mut self) -> bool {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        match self.sender.take() {
+            Some(sender) => sender.await.unwrap_or(false),
+            None => true,
+        }
+    }
+}
+
+impl Drop for CloudLoggingGuard {
+    /// Tells the background task to send the entries still queued, without waiting
+    /// for it: prefer [`CloudLoggingGuard::shutdown`].
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+/// Maps a structured logging line to an API `LogEntry`: the special fields move to
+/// their `LogEntry` members and the others make up the `jsonPayload`.
+fn to_api_entry(line: &[u8]) -> Value {
+    let Ok(Value::Object(mut payload)) = serde_json::from_slice(line) else {
+        let text = String::from_utf8_lossy(line);
+        return json!({ "textPayload": text.trim_end() });
+    };
+    let mut entry = Map::new();
+    for (key, api_key) in [
+        ("severity", "severity"),
+        ("time", "timestamp"),
+        ("logging.googleapis.com/trace", "trace"),
+        ("logging.googleapis.com/spanId", "spanId"),
+        ("logging.googleapis.com/trace_sampled", "traceSampled"),
+        ("logging.googleapis.com/sourceLocation", "sourceLocation"),
+        ("logging.googleapis.com/insertId", "insertId"),
+        ("logging.googleapis.com/operation", "operation"),
+        ("logging.googleapis.com/labels", "labels"),
+        ("httpRequest", "httpRequest"),
+        ("logName", "logName"),
+        ("resource", "resource"),
+    ] {
+        if let Some(value) = payload.remove(key) {
+            entry.insert(api_key.into(), value);
+        }
+    }
+    if let Some(Value::Object(timestamp)) = payload.remove("timestamp") {
+        let seconds = timestamp.get("seconds").and_then(Value::as_i64).unwrap_or(0);
+        let nanos = timestamp.get("nanos").and_then(Value::as_u64).unwrap_or(0);
+        if let Some(time) = DateTime::from_timestamp(seconds, nanos as u32) {
+            entry.insert("timestamp".into(), time.to_rfc3339().into());
+        }
+    }
+    entry.insert("jsonPayload".into(), payload.into());
+    entry.into()
+}
@@ -0,0 +1,1781 @@
+//! Pieces shared by the `with-tracing` and `with-log4rs` examples.
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+mod batch;
+#[cfg(feature = "test-util")]
+mod capture;
+#[cfg(feature = "cloud-logging")]
+mod cloud_logging;
+#[cfg(feature = "tower")]
+pub mod middleware;
+mod panic_reporter;
+mod pre_init;
+#[cfg(feature = "protobuf")]
+mod protobuf;
+#[cfg(feature = "tokio")]
+mod trace_scope;
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub use batch::{BatchGuard, BatchWriter};
+#[cfg(feature = "test-util")]
+pub use capture::{CaptureWriter, FixedClock};
+#[cfg(feature = "cloud-logging")]
+pub use cloud_logging::{
+    CloudLoggingBuilder, CloudLoggingGuard, CloudLoggingWriter, ENTRIES_WRITE_URL,
+    TokenProvider,
+};
+pub use panic_reporter::install_panic_reporter;
+pub use pre_init::PreInitBuffer;
+#[cfg(feature = "tokio")]
+pub use trace_scope::{
+    CorrelationScoped, TASK_LOCAL_CORRELATION_ID, TASK_LOCAL_TRACE_ID, TraceScoped,
+    current_trace_id, with_correlation_id, with_trace_id,
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Serialize, Serializer};
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Once, PoisonError};
+
+/// A structured log line as understood by Cloud Logging.
+///
+/// Special fields use the keys documented in
+/// <https://cloud.google.com/logging/docs/structured-logging#special-payload-fields>.
+/// Custom sinks can build entries with [`LogEntry::new`] and the public fields, and
+/// rely on these keys staying the same.
+///
+/// Every member but `severity` and the time is omitted when None or empty, so an entry
+/// made by [`LogEntry::new`] serializes to exactly `severity`, `message` and `time`
+/// (or `timestamp`). New members must keep to this.
+///
+/// [`EntryKeys::Generic`] writes the members under plain keys instead.
+///
+/// Members are written in a fixed order: the special fields in the order below, then
+/// the other fields sorted by key, labels included, so that identical entries
+/// serialize to identical lines.
+#[derive(Serialize)]
+#[doc(alias = "GcpLogEntry")]
+pub struct LogEntry<'a> {
+    /// `severity`: a `LogSeverity` name, see [`level_to_gcp_severity`]. Omitted when
+    /// empty, once [`LogEntry::use_numeric_severity`] moved it to the fields.
+    #[serde(skip_serializing_if = "str::is_empty")]
+    pub severity: &'a str,
+    /// `message`: the text shown in the log viewer. Omitted when empty.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub message: String,
+    /// `time` or `timestamp`, see [`Timestamp`].
+    #[serde(flatten, skip_serializing_if = "Timestamp::is_moved")]
+    pub time: Timestamp,
+    /// `logging.googleapis.com/trace`: see [`format_trace`].
+    #[serde(rename = "logging.googleapis.com/trace")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace: Option<String>,
+    /// `logging.googleapis.com/spanId`
+    #[serde(rename = "logging.googleapis.com/spanId")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub span_id: Option<String>,
+    /// `logging.googleapis.com/trace_sampled`
+    #[serde(rename = "logging.googleapis.com/trace_sampled")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace_sampled: Option<bool>,
+    /// `logging.googleapis.com/sourceLocation`
+    #[serde(rename = "logging.googleapis.com/sourceLocation")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_location: Option<SourceLocation<'a>>,
+    /// `httpRequest`
+    #[serde(rename = "httpRequest")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_request: Option<HttpRequest>,
+    /// `logging.googleapis.com/insertId`, see [`InsertIdGenerator`].
+    #[serde(rename = "logging.googleapis.com/insertId")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub insert_id: Option<String>,
+    /// `logName`: the log the entry is routed to, see [`format_log_name`].
+    #[serde(rename = "logName")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_name: Option<&'a str>,
+    /// `@type`: [`REPORTED_ERROR_EVENT_TYPE`] makes Error Reporting pick the entry up.
+    #[serde(rename = "@type")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_type: Option<&'static str>,
+    /// `serviceContext`: the service Error Reporting groups the error under.
+    #[serde(rename = "serviceContext")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_context: Option<&'a ServiceContext>,
+    /// `logging.googleapis.com/operation`
+    #[serde(rename = "logging.googleapis.com/operation")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operation: Option<Operation>,
+    /// `resource`: the monitored resource that produced the entry.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resource: Option<&'a MonitoredResource>,
+    /// `logging.googleapis.com/labels`
+    #[serde(rename = "logging.googleapis.com/labels")]
+    #[serde(skip_serializing_if = "HashMap::is_empty", serialize_with = "sorted")]
+    pub labels: HashMap<String, String>,
+    /// Any other member ends up in the entry's `jsonPayload`.
+    #[serde(flatten)]
+    pub fields: Map<String, Value>,
+    /// The key [`LogEntry::rename_message`] moves the message under. Not serialized.
+    #[serde(skip)]
+    pub message_key: Option<String>,
+    /// The key [`LogEntry::rename_time`] moved the time under. Not serialized.
+    #[serde(skip)]
+    pub time_key: Option<String>,
+}
+
+impl<'a> LogEntry<'a> {
+    /// An entry with only `severity`, `message` and the time set.
+    pub fn new(severity: &'a str, message: String, time: Timestamp) -> Self {
+        LogEntry {
+            severity,
+            message,
+            time,
+            trace: None,
+            span_id: None,
+            trace_sampled: None,
+            source_location: None,
+            http_request: None,
+            insert_id: None,
+            log_name: None,
+            error_type: None,
+            service_context: None,
+            operation: None,
+            resource: None,
+            labels: HashMap::new(),
+            fields: Map::new(),
+            message_key: None,
+            time_key: None,
+        }
+    }
+
+    /// Starts building an entry logged now, see [`LogEntryBuilder`].
+    pub fn builder(severity: &'a str, message: impl Into<String>) -> LogEntryBuilder<'a> {
+        let time = Timestamp::new(SystemClock.now(), TimestampFormat::default());
+        LogEntryBuilder(LogEntry::new(severity, message.into(), time))
+    }
+
+    /// Serializes the entry as a single line of JSON, ending with a newline. Fails if a
+    /// field's `Serialize` implementation does.
+    pub fn to_json_line(&self) -> serde_json::Result<String> {
+        let mut line = serde_json::to_string(self)?;
+        line.push('\n');
+        Ok(line)
+    }
+
+    /// Moves the message under `key`, for pipelines that expect it somewhere other
+    /// than `message`.
+    pub fn rename_message(&mut self, key: &str) {
+        if key == "message" {
+            return;
+        }
+        self.message_key = Some(key.into());
+        if !self.message.is_empty() {
+            let message = std::mem::take(&mut self.message);
+            self.fields.insert(key.into(), message.into());
+        }
+    }
+
+    /// Moves the time under `key`, for pipelines that expect it somewhere other than
+    /// `time` (or `timestamp`, depending on the [`TimestampFormat`]).
+    pub fn rename_time(&mut self, key: &str) {
+        let value = match &self.time {
+            Timestamp::Rfc3339(_) if key == "time" => return,
+            Timestamp::SecondsNanos { .. } if key == "timestamp" => return,
+            Timestamp::Moved => return,
+            Timestamp::Rfc3339(time) => Value::from(time.as_str()),
+            Timestamp::SecondsNanos { seconds, nanos } => {
+                serde_json::json!({ "seconds": seconds, "nanos": nanos })
+            }
+        };
+        self.time = Timestamp::Moved;
+        self.time_key = Some(key.into());
+        self.fields.insert(key.into(), value);
+    }
+
+    /// Writes `severity` as its number rather than its name, e.g. 400 for WARNING, for
+    /// tools that expect numeric severities. See [`severity_number`].
+    pub fn use_numeric_severity(&mut self) {
+        if let Some(number) = severity_number(self.severity) {
+            self.severity = "";
+            self.fields.insert("severity".into(), number.into());
+        }
+    }
+}
+
+/// Builds a [`LogEntry`], for sinks that write entries themselves:
+///
+/// ```
+/// use gcp_logging_core::{LogEntry, Timestamp};
+///
+/// let entry = LogEntry::builder("WARNING", "disk almost full")
+///     .time(Timestamp::Rfc3339("2024-05-01T12:00:00.000Z".into()))
+///     .trace("projects/my-project/traces/4bf92f3577b34da6a3ce929d0e0e4736")
+///     .label("service", "api")
+///     .field("free_bytes", 1024)
+///     .build();
+/// assert_eq!(
+///     entry.to_json_line()?,
+///     concat!(
+///         r#"{"severity":"WARNING","message":"disk almost full","#,
+///         r#""time":"2024-05-01T12:00:00.000Z","#,
+///         r#""logging.googleapis.com/trace":"#,
+///         r#""projects/my-project/traces/4bf92f3577b34da6a3ce929d0e0e4736","#,
+///         r#""logging.googleapis.com/labels":{"service":"api"},"free_bytes":1024}"#,
+///         "\n",
+///     )
+/// );
+/// # Ok::<(), serde_json::Error>(())
+/// ```
+pub struct LogEntryBuilder<'a>(LogEntry<'a>);
+
+impl<'a> LogEntryBuilder<'a> {
+    pub fn time(mut self, time: Timestamp) -> Self {
+        self.0.time = time;
+        self
+    }
+
+    /// Sets `logging.googleapis.com/trace`, e.g. to the result of [`format_trace`].
+    pub fn trace(mut self, trace: impl Into<String>) -> Self {
+        self.0.trace = Some(trace.into());
+        self
+    }
+
+    pub fn span_id(mut self, span_id: impl Into<String>) -> Self {
+        self.0.span_id = Some(span_id.into());
+        self
+    }
+
+    pub fn trace_sampled(mut self, trace_sampled: bool) -> Self {
+        self.0.trace_sampled = Some(trace_sampled);
+        self
+    }
+
+    pub fn source_location(mut self, source_location: SourceLocation<'a>) -> Self {
+        self.0.source_location = Some(source_location);
+        self
+    }
+
+    pub fn http_request(mut self, http_request: HttpRequest) -> Self {
+        self.0.http_request = Some(http_request);
+        self
+    }
+
+    pub fn insert_id(mut self, insert_id: impl Into<String>) -> Self {
+        self.0.insert_id = Some(insert_id.into());
+        self
+    }
+
+    /// Sets `logName`, see [`format_log_name`].
+    pub fn log_name(mut self, log_name: &'a str) -> Self {
+        self.0.log_name = Some(log_name);
+        self
+    }
+
+    /// Marks the entry as an Error Reporting event of `service_context`.
+    pub fn reported_error(mut self, service_context: &'a ServiceContext) -> Self {
+        self.0.error_type = Some(REPORTED_ERROR_EVENT_TYPE);
+        self.0.service_context = Some(service_context);
+        self
+    }
+
+    pub fn operation(mut self, operation: Operation) -> Self {
+        self.0.operation = Some(operation);
+        self
+    }
+
+    pub fn resource(mut self, resource: &'a MonitoredResource) -> Self {
+        self.0.resource = Some(resource);
+        self
+    }
+
+    /// Adds a `logging.googleapis.com/labels` member.
+    pub fn label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.0.labels.insert(key.into(), value.into());
+        self
+    }
+
+    /// Adds a member of the entry's `jsonPayload`.
+    pub fn field(mut self, key: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.0.fields.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn build(self) -> LogEntry<'a> {
+        self.0
+    }
+}
+
+/// The `file`, `line` and `function` members of `logging.googleapis.com/sourceLocation`.
+#[derive(Serialize)]
+pub struct SourceLocation<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<u32>,
+    /// Rust has no stable way to name the calling function, so the backends put the
+    /// module path here, or else the target.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function: Option<&'a str>,
+}
+
+/// Source of the time stamped on entries, replaceable to freeze time in tests.
+pub trait Clock {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The wall clock. Not available on `wasm32-unknown-unknown`, where reading it
+/// panics: use a [`FnClock`] there.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock reading the time from a function, e.g. one imported from the wasm host.
+pub struct FnClock<F>(pub F);
+
+impl<F: Fn() -> DateTime<Utc>> Clock for FnClock<F> {
+    fn now(&self) -> DateTime<Utc> {
+        (self.0)()
+    }
+}
+
+/// How [`Timestamp`] renders the time of an entry.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum TimestampFormat {
+    #[default]
+    Rfc3339,
+    SecondsNanos,
+}
+
+#[derive(Serialize)]
+pub enum Timestamp {
+    /// `time`: RFC3339 string with millisecond precision.
+    #[serde(rename = "time")]
+    Rfc3339(String),
+    /// `timestamp`: `{"seconds": ..., "nanos": ...}`, keeping nanosecond precision.
+    #[serde(rename = "timestamp")]
+    SecondsNanos { seconds: i64, nanos: u32 },
+    /// Moved to the fields under another key by [`LogEntry::rename_time`].
+    #[serde(skip)]
+    Moved,
+}
+
+impl Timestamp {
+    pub fn new(time: DateTime<Utc>, format: TimestampFormat) -> Self {
+        match format {
+            TimestampFormat::Rfc3339 => {
+                Timestamp::Rfc3339(time.to_rfc3339_opts(SecondsFormat::Millis, true))
+            }
+            TimestampFormat::SecondsNanos => Timestamp::SecondsNanos {
+                seconds: time.timestamp(),
+                nanos: time.timestamp_subsec_nanos(),
+            },
+        }
+    }
+
+    fn is_moved(&self) -> bool {
+        matches!(self, Timestamp::Moved)
+    }
+}
+
+/// Produces unique `insertId`s so Cloud Logging doesn't merge entries sharing a
+/// timestamp: a per-process counter followed by a random per-process suffix.
+pub struct InsertIdGenerator {
+    counter: AtomicU64,
+    suffix: u64,
+}
+
+impl InsertIdGenerator {
+    pub fn new() -> Self {
+        let suffix = RandomState::new().build_hasher().finish();
+        InsertIdGenerator { counter: AtomicU64::new(0), suffix }
+    }
+
+    pub fn next_id(&self) -> String {
+        let n = self.counter.fetch_add(1, Ordering::Relaxed);
+        format!("{n:016x}-{:016x}", self.suffix)
+    }
+}
+
+impl Default for InsertIdGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A random id for the `correlation_id` label, 16 hex digits.
+pub fn new_correlation_id() -> String {
+    format!("{:016x}", RandomState::new().build_hasher().finish())
+}
+
+/// A random (version 4) UUID, for the id of an operation that wasn't given one.
+pub fn new_operation_id() -> String {
+    let random = |_| RandomState::new().build_hasher().finish();
+    let [high, low]: [u64; 2] = std::array::from_fn(random);
+    let high = high & !0xf000 | 0x4000;
+    let low = low & !(0xc << 60) | 0x8 << 60;
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32,
+        high >> 16 & 0xffff,
+        high & 0xffff,
+        low >> 48,
+        low & 0xffff_ffff_ffff
+    )
+}
+
+/// The `httpRequest` member of an entry, mirroring GCP's `HttpRequest` message.
+/// `latency` is a duration string such as `"0.25s"`.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HttpRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub referer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<String>,
+}
+
+thread_local! {
+    static LINE_BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Serializes `entry` followed by a newline into a reused per-thread buffer and hands
+/// the line to `write`. Both backends write entries through this function.
+///
+/// Cloud Logging reads one entry per line. Compact JSON escapes the newlines inside
+/// strings, so a multiline `message` still yields exactly one line.
+pub fn with_serialized_line<R>(
+    entry: &LogEntry,
+    write: impl FnOnce(&[u8]) -> R,
+) -> serde_json::Result<R> {
+    with_serialized_entry(entry, false, write)
+}
+
+/// Like [`with_serialized_line`], but indents the JSON over several lines when
+/// `pretty` is set. Cloud Logging can't parse pretty entries: only use it to read
+/// logs locally.
+pub fn with_serialized_entry<R>(
+    entry: &LogEntry,
+    pretty: bool,
+    write: impl FnOnce(&[u8]) -> R,
+) -> serde_json::Result<R> {
+    with_serialized(entry, pretty, write)
+}
+
+/// Serializes `value` into the per-thread buffer, which is cleared first so that
+/// whatever a failed serialization left in it is never written.
+fn with_serialized<R>(
+    value: &impl Serialize,
+    pretty: bool,
+    write: impl FnOnce(&[u8]) -> R,
+) -> serde_json::Result<R> {
+    let serialize = |buf: &mut Vec<u8>| {
+        buf.clear();
+        if pretty {
+            serde_json::to_writer_pretty(&mut *buf, value)?;
+        } else {
+            serde_json::to_writer(&mut *buf, value)?;
+            debug_assert!(!buf.contains(&b'\n'), "compact JSON spans a single line");
+        }
+        buf.push(b'\n');
+        Ok(write(buf))
+    };
+    LINE_BUFFER.with(|buf| match buf.try_borrow_mut() {
+        Ok(mut buf) => serialize(&mut buf),
+        // `write` itself logged something: don't clobber the outer line.
+        Err(_) => serialize(&mut Vec::new()),
+    })
+}
+
+/// The largest entry Cloud Logging accepts, in bytes.
+pub const MAX_ENTRY_SIZE: usize = 256 * 1024;
+
+/// What to do with an entry whose JSON is over the size limit, which Cloud Logging
+/// would reject.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum OversizedEntry {
+    /// Writes a WARNING entry noting the size instead, keeping the trace and labels.
+    #[default]
+    Drop,
+    /// Cuts the longest string field, or the message, until the entry fits. Drops it
+    /// when that isn't enough.
+    TruncateLargestField,
+}
+
+impl OversizedEntry {
+    /// Makes `entry` serialize to at most `max_size` bytes.
+    pub fn fit(self, entry: &mut LogEntry, max_size: usize) {
+        let mut size = serialized_size(entry);
+        if size <= max_size {
+            return;
+        }
+        if let OversizedEntry::TruncateLargestField = self {
+            while let Some(largest) = largest_string(entry) {
+                // Escapes make the JSON of the string longer than the string: cut it
+                // in proportion, leaving room for the 3 bytes of `…`.
+                let mut excess = size - max_size + 3;
+                // A string cut in a previous round trades its `…` for the new one.
+                if let Some(len) = largest.strip_suffix('…').map(str::len) {
+                    largest.truncate(len);
+                    excess -= 3;
+                }
+                let json_len = serde_json::to_string(largest).map_or(0, |j| j.len());
+                let len =
+                    largest.len() * json_len.saturating_sub(excess) / json_len.max(1);
+                truncate_string(largest, len);
+                entry.fields.insert("truncated".into(), true.into());
+                let previous_size = std::mem::replace(&mut size, serialized_size(entry));
+                if size <= max_size {
+                    return;
+                }
+                if size >= previous_size {
+                    break;
+                }
+            }
+        }
+        // `use_numeric_severity` empties the name of numeric severities.
+        let numeric_severity = entry.severity.is_empty();
+        entry.severity = "WARNING";
+        entry.message =
+            format!("dropped a {size} byte log entry, over the {max_size} byte limit");
+        // The renamed time stays, and the message goes under the renamed key.
+        let time = entry.time_key.as_ref().and_then(|key| entry.fields.remove_entry(key));
+        entry.fields.clear();
+        entry.fields.extend(time);
+        if let Some(message_key) = entry.message_key.take() {
+            entry.rename_message(&message_key);
+        }
+        if numeric_severity {
+            entry.use_numeric_severity();
+        }
+        entry.http_request = None;
+        entry.error_type = None;
+        entry.service_context = None;
+    }
+}
+
+/// Writes `line`, which ends with a newline as [`with_serialized_line`] makes them,
+/// with `delimiter` in place of the newline, e.g. `b'\0'` for collectors that read
+/// NUL-delimited JSON. The record is written with a single `write_all`.
+pub fn write_delimited(
+    writer: &mut (impl Write + ?Sized),
+    line: &[u8],
+    delimiter: u8,
+) -> io::Result<()> {
+    match line.split_last() {
+        Some((b'\n', record)) if delimiter != b'\n' => {
+            let mut delimited = Vec::with_capacity(line.len());
+            delimited.extend_from_slice(record);
+            delimited.push(delimiter);
+            writer.write_all(&delimited)
+        }
+        _ => writer.write_all(line),
+    }
+}
+
+/// Shares a writer between threads behind a `Mutex`. Both backends hand each entry
+/// over in a single `write_all`, which this writes while holding the lock, so entries
+/// written concurrently never interleave, even with writers such as a `File` that
+/// may split them into several writes. Clones share the writer.
+#[derive(Debug, Default)]
+pub struct LockedWriter<W> {
+    inner: Arc<Mutex<W>>,
+}
+
+impl<W> LockedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        LockedWriter { inner: Arc::new(Mutex::new(inner)) }
+    }
+}
+
+impl<W> Clone for LockedWriter<W> {
+    fn clone(&self) -> Self {
+        LockedWriter { inner: self.inner.clone() }
+    }
+}
+
+impl<W: Write> Write for LockedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner).flush()
+    }
+}
+
+fn serialized_size(entry: &LogEntry) -> usize {
+    serde_json::to_vec(entry).map_or(0, |json| json.len() + 1)
+}
+
+/// The message or top-level string field with the most bytes.
+fn largest_string<'e>(entry: &'e mut LogEntry) -> Option<&'e mut String> {
+    let fields = entry.fields.values_mut().filter_map(|v| match v {
+        Value::String(s) => Some(s),
+        _ => None,
+    });
+    std::iter::once(&mut entry.message)
+        .chain(fields)
+        .max_by_key(|s| s.len())
+        .filter(|s| !s.is_empty())
+}
+
+/// The keys entries are written under.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EntryKeys {
+    /// The special keys of Cloud Logging, e.g. `logging.googleapis.com/trace`.
+    #[default]
+    Gcp,
+    /// Plain keys named after the members of [`LogEntry`], e.g. `trace`, `span_id`
+    /// and `labels`, for deployments outside of GCP. Cloud Logging, and the
+    /// `cloud-logging` sink, would keep them in the `jsonPayload`.
+    Generic,
+}
+
+/// A [`LogEntry`] under [`EntryKeys::Generic`] keys.
+#[derive(Serialize)]
+struct GenericLogEntry<'e, 'a> {
+    #[serde(skip_serializing_if = "str::is_empty")]
+    severity: &'a str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    message: &'e str,
+    #[serde(flatten, skip_serializing_if = "Timestamp::is_moved")]
+    time: &'e Timestamp,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trace: Option<&'e str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    span_id: Option<&'e str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trace_sampled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_location: Option<&'e SourceLocation<'a>>,
+    #[serde(rename = "httpRequest")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    http_request: Option<&'e HttpRequest>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    insert_id: Option<&'e str>,
+    #[serde(rename = "logName")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    log_name: Option<&'a str>,
+    #[serde(rename = "@type")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_type: Option<&'static str>,
+    #[serde(rename = "serviceContext")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    service_context: Option<&'a ServiceContext>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    operation: Option<&'e Operation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resource: Option<&'a MonitoredResource>,
+    #[serde(skip_serializing_if = "HashMap::is_empty", serialize_with = "sorted")]
+    labels: &'e HashMap<String, String>,
+    #[serde(flatten)]
+    fields: &'e Map<String, Value>,
+}
+
+impl<'e, 'a> From<&'e LogEntry<'a>> for GenericLogEntry<'e, 'a> {
+    fn from(entry: &'e LogEntry<'a>) -> Self {
+        GenericLogEntry {
+            severity: entry.severity,
+            message: &entry.message,
+            time: &entry.time,
+            trace: entry.trace.as_deref(),
+            span_id: entry.span_id.as_deref(),
+            trace_sampled: entry.trace_sampled,
+            source_location: entry.source_location.as_ref(),
+            http_request: entry.http_request.as_ref(),
+            insert_id: entry.insert_id.as_deref(),
+            log_name: entry.log_name,
+            error_type: entry.error_type,
+            service_context: entry.service_context,
+            operation: entry.operation.as_ref(),
+            resource: entry.resource,
+            labels: &entry.labels,
+            fields: &entry.fields,
+        }
+    }
+}
+
+/// Like [`with_serialized_entry`], under the keys `keys` says.
+fn with_serialized_keys<R>(
+    entry: &LogEntry,
+    keys: EntryKeys,
+    pretty: bool,
+    write: impl FnOnce(&[u8]) -> R,
+) -> serde_json::Result<R> {
+    match keys {
+        EntryKeys::Gcp => with_serialized(entry, pretty, write),
+        EntryKeys::Generic => {
+            with_serialized(&GenericLogEntry::from(entry), pretty, write)
+        }
+    }
+}
+
+/// Like [`with_serialized_entry`], under the keys `keys` says, but first makes entries
+/// over `max_size` bytes fit as `oversized` says.
+pub fn with_size_limited_entry<R>(
+    entry: &mut LogEntry,
+    keys: EntryKeys,
+    pretty: bool,
+    (max_size, oversized): (usize, OversizedEntry),
+    write: impl FnOnce(&[u8]) -> R,
+) -> serde_json::Result<R> {
+    if pretty {
+        // Cloud Logging would measure the compact JSON.
+        oversized.fit(entry, max_size);
+        return with_serialized_keys(entry, keys, true, write);
+    }
+    let mut write = Some(write);
+    let written = with_serialized_keys(entry, keys, false, |line| {
+        (line.len() <= max_size)
+            .then(|| write.take().expect("entries are written once")(line))
+    })?;
+    if let Some(written) = written {
+        return Ok(written);
+    }
+    oversized.fit(entry, max_size);
+    let write = write.take().expect("the entry wasn't written");
+    with_serialized_keys(entry, keys, false, write)
+}
+
+/// How [`write_entry`] writes entries, as configured on the builders of the backends.
+#[derive(Clone, Copy, Debug)]
+pub struct WriteOptions {
+    /// Indents entries over several lines, for reading logs locally.
+    pub pretty: bool,
+    /// Writes `severity` as its `LogSeverity` number, e.g. 400 for WARNING.
+    pub numeric_severity: bool,
+    /// Ends entries with this byte rather than a newline, see [`write_delimited`].
+    pub record_delimiter: u8,
+    pub payload_format: PayloadFormat,
+    /// How entries over a size, by default Cloud Logging's limit, are handled.
+    pub entry_size_limit: (usize, OversizedEntry),
+    pub entry_keys: EntryKeys,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions {
+            pretty: false,
+            numeric_severity: false,
+            record_delimiter: b'\n',
+            payload_format: PayloadFormat::default(),
+            entry_size_limit: (MAX_ENTRY_SIZE, OversizedEntry::Drop),
+            entry_keys: EntryKeys::Gcp,
+        }
+    }
+}
+
+/// Writes `entry` to `w` as `options` say, with a single `write_all`: as a JSON line,
+/// or as plain text for [`PayloadFormat::TextIfUnstructured`]. Both backends write
+/// their entries with it, but any `io::Write` will do, e.g. a `Vec<u8>` to check the
+/// bytes of an entry.
+pub fn write_entry<W: Write + ?Sized>(
+    w: &mut W,
+    entry: &mut LogEntry,
+    options: WriteOptions,
+) -> Result<(), LogError> {
+    if let Some(text) = options.payload_format.text_payload(entry) {
+        let line = format!("{text}\n");
+        return Ok(write_delimited(w, line.as_bytes(), options.record_delimiter)?);
+    }
+    if options.numeric_severity {
+        entry.use_numeric_severity();
+    }
+    let (keys, pretty, limit) =
+        (options.entry_keys, options.pretty, options.entry_size_limit);
+    with_size_limited_entry(entry, keys, pretty, limit, |line| {
+        write_delimited(w, line, options.record_delimiter)
+    })??;
+    Ok(())
+}
+
+/// Why an entry couldn't be written, handed to the `on_error` callback of the
+/// backends.
+#[derive(Debug)]
+pub enum LogError {
+    /// The entry couldn't be serialized, e.g. a field's `Serialize` impl failed.
+    Serialize(serde_json::Error),
+    /// The writer failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogError::Serialize(e) => write!(f, "failed to serialize log entry: {e}"),
+            LogError::Io(e) => write!(f, "failed to write log entry: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for LogError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LogError::Serialize(e) => Some(e),
+            LogError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<serde_json::Error> for LogError {
+    fn from(e: serde_json::Error) -> Self {
+        LogError::Serialize(e)
+    }
+}
+
+impl From<io::Error> for LogError {
+    fn from(e: io::Error) -> Self {
+        LogError::Io(e)
+    }
+}
+
+/// Handles an entry that couldn't be serialized or written, e.g. by counting it.
+pub type OnError = Box<dyn Fn(&LogError) + Send + Sync>;
+
+/// The default `on_error` callback: prints a line to stderr, bypassing the logger.
+pub fn eprint_log_error(error: &LogError) {
+    let _ = writeln!(io::stderr().lock(), "{error}");
+}
+
+/// Hands each entry, without its trailing newline, to a function on the calling
+/// thread, e.g. one logging to the console of the wasm host.
+pub struct FnWriter<F>(pub F);
+
+impl<F: FnMut(&str)> Write for FnWriter<F> {
+    /// Expects a whole entry per call, as [`with_serialized_line`] writes them.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let line = std::str::from_utf8(buf).map_err(io::Error::other)?;
+        (self.0)(line.trim_end_matches('\n'));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub const REPORTED_ERROR_EVENT_TYPE: &str =
+    "type.googleapis.com/google.devtools.clouderrorreporting.v1beta1.ReportedErrorEvent";
+
+/// The `serviceContext` of entries reported to Error Reporting.
+#[derive(Clone, Debug, Serialize)]
+pub struct ServiceContext {
+    pub service: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+
+/// The `logging.googleapis.com/operation` of an entry, grouping the entries of one
+/// long-running operation.
+#[derive(Clone, Debug, Serialize)]
+pub struct Operation {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub producer: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub first: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub last: bool,
+}
+
+/// The `resource` of an entry, e.g. a `cloud_run_revision`.
+#[derive(Clone, Debug, Serialize)]
+pub struct MonitoredResource {
+    #[serde(rename = "type")]
+    pub resource_type: String,
+    #[serde(serialize_with = "sorted")]
+    pub labels: HashMap<String, String>,
+}
+
+/// Serializes a map sorted by key rather than in its random iteration order.
+fn sorted<S: Serializer>(map: &HashMap<String, String>, s: S) -> Result<S::Ok, S::Error> {
+    s.collect_map(map.iter().collect::<BTreeMap<_, _>>())
+}
+
+impl MonitoredResource {
+    /// The `cloud_run_revision` resource described by the `K_SERVICE`, `K_REVISION`
+    /// and `K_CONFIGURATION` env vars Cloud Run sets. None outside of Cloud Run.
+    pub fn detect_cloud_run() -> Option<Self> {
+        let service_name = std::env::var("K_SERVICE").ok()?;
+        let mut labels = HashMap::from([("service_name".to_owned(), service_name)]);
+        for (var, label) in
+            [("K_REVISION", "revision_name"), ("K_CONFIGURATION", "configuration_name")]
+        {
+            if let Ok(value) = std::env::var(var) {
+                labels.insert(label.into(), value);
+            }
+        }
+        Some(MonitoredResource { resource_type: "cloud_run_revision".into(), labels })
+    }
+}
+
+/// Builds the `projects/PROJECT_ID/traces/TRACE_ID` value of
+/// `logging.googleapis.com/trace`, or `None` if `trace_id` does not pass
+/// [`normalize_trace_id`]. The first invalid id is reported on stderr.
+pub fn format_trace(project_id: &str, trace_id: &str) -> Option<String> {
+    format_trace_with_prefix(&trace_prefix(project_id), trace_id)
+}
+
+/// The `projects/PROJECT_ID/traces/` part of [`format_trace`], for callers formatting
+/// many traces of the same project with [`format_trace_with_prefix`].
+pub fn trace_prefix(project_id: &str) -> String {
+    format!("projects/{project_id}/traces/")
+}
+
+/// Like [`format_trace`], given the [`trace_prefix`] of the project.
+pub fn format_trace_with_prefix(prefix: &str, trace_id: &str) -> Option<String> {
+    static WARN_INVALID: Once = Once::new();
+    if !is_trace_id(trace_id) {
+        WARN_INVALID.call_once(|| {
+            eprintln!(
+                "dropping invalid trace id {trace_id:?}: expected 32 hex characters"
+            );
+        });
+        return None;
+    }
+    let mut trace = String::with_capacity(prefix.len() + trace_id.len());
+    trace.push_str(prefix);
+    trace.extend(trace_id.chars().map(|c| c.to_ascii_lowercase()));
+    Some(trace)
+}
+
+/// Lowercases `trace_id`, returning `None` unless it is the 32 hex characters Cloud
+/// Trace expects.
+pub fn normalize_trace_id(trace_id: &str) -> Option<String> {
+    is_trace_id(trace_id).then(|| trace_id.to_ascii_lowercase())
+}
+
+/// Builds the `projects/PROJECT_ID/logs/LOG_ID` value of `logName`, URL-encoding
+/// `log_id` so that e.g. a `/` in it stays part of the name.
+pub fn format_log_name(project_id: &str, log_id: &str) -> String {
+    let mut log_name = format!("projects/{project_id}/logs/");
+    for b in log_id.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_') {
+            log_name.push(b as char);
+        } else {
+            log_name.push_str(&format!("%{b:02X}"));
+        }
+    }
+    log_name
+}
+
+/// Parses a W3C `traceparent` header (`00-<32 hex trace id>-<16 hex span id>-<flags>`)
+/// into the trace id, the span id and the sampled flag.
+pub fn parse_traceparent(header: &str) -> Option<(String, String, bool)> {
+    let mut parts = header.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let span_id = parts.next()?;
+    let flags = parts.next()?;
+    if !is_hex(version, 2)
+        || version == "ff"
+        || (version == "00" && parts.next().is_some())
+    {
+        return None;
+    }
+    if !is_hex(trace_id, 32) || !is_hex(span_id, 16) || !is_hex(flags, 2) {
+        return None;
+    }
+    if trace_id.bytes().all(|b| b == b'0') || span_id.bytes().all(|b| b == b'0') {
+        return None;
+    }
+    let sampled = u8::from_str_radix(flags, 16).ok()? & 0x01 == 0x01;
+    Some((trace_id.to_owned(), span_id.to_owned(), sampled))
+}
+
+/// Parses an `X-Cloud-Trace-Context: TRACE_ID/SPAN_ID;o=OPTIONS` header into the
+/// trace id, the decimal span id and the sampled flag (`o=1`).
+pub fn parse_cloud_trace_context(
+    header: &str,
+) -> Option<(String, Option<u64>, Option<bool>)> {
+    let (rest, sampled) = match header.trim().split_once(";o=") {
+        Some((rest, "1")) => (rest, Some(true)),
+        Some((rest, "0")) => (rest, Some(false)),
+        Some(_) => return None,
+        None => (header.trim(), None),
+    };
+    let (trace_id, span_id) = match rest.split_once('/') {
+        Some((trace_id, span_id)) => (trace_id, Some(span_id.parse().ok()?)),
+        None => (rest, None),
+    };
+    if trace_id.is_empty() {
+        return None;
+    }
+    Some((trace_id.to_owned(), span_id, sampled))
+}
+
+/// The trace id, hex span id and sampled flag read from a request header.
+pub type TraceContext = (String, Option<String>, Option<bool>);
+
+/// Parses the value of a trace context header.
+pub type TraceContextParser = fn(&str) -> Option<TraceContext>;
+
+/// Reads the trace context of a request from the first of several headers that is
+/// present and parses. The default tries `X-Cloud-Trace-Context`, then `traceparent`.
+#[derive(Clone, Debug)]
+pub struct TraceContextExtractor {
+    headers: Vec<(String, TraceContextParser)>,
+}
+
+impl TraceContextExtractor {
+    /// An extractor trying no header: add them in order with [`header`](Self::header).
+    pub fn new() -> Self {
+        TraceContextExtractor { headers: Vec::new() }
+    }
+
+    /// Tries `name` with `parser` after the headers already added.
+    pub fn header(mut self, name: &str, parser: TraceContextParser) -> Self {
+        self.headers.push((name.to_ascii_lowercase(), parser));
+        self
+    }
+
+    /// Tries the `X-Cloud-Trace-Context` header after the headers already added.
+    pub fn cloud_trace_context(self) -> Self {
+        self.header("x-cloud-trace-context", |value| {
+            let (trace_id, span_id, sampled) = parse_cloud_trace_context(value)?;
+            Some((trace_id, span_id.map(|s| format!("{s:016x}")), sampled))
+        })
+    }
+
+    /// Tries the W3C `traceparent` header after the headers already added.
+    pub fn traceparent(self) -> Self {
+        self.header("traceparent", |value| {
+            let (trace_id, span_id, sampled) = parse_traceparent(value)?;
+            Some((trace_id, Some(span_id), Some(sampled)))
+        })
+    }
+
+    /// Returns the context of the first header that `header_value` finds and that
+    /// parses. `header_value` is given lowercase header names.
+    pub fn extract<'h>(
+        &self,
+        header_value: impl Fn(&str) -> Option<&'h str>,
+    ) -> Option<TraceContext> {
+        self.headers.iter().find_map(|(name, parser)| parser(header_value(name)?))
+    }
+}
+
+impl Default for TraceContextExtractor {
+    fn default() -> Self {
+        TraceContextExtractor::new().cloud_trace_context().traceparent()
+    }
+}
+
+/// Like `is_hex(trace_id, 32)`, but also accepts uppercase digits.
+fn is_trace_id(trace_id: &str) -> bool {
+    trace_id.len() == 32 && trace_id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Converts a float field to JSON, which has no NaN or infinities: those become the
+/// strings `"NaN"`, `"Infinity"` and `"-Infinity"` rather than `null`.
+pub fn float_to_json(value: f64) -> Value {
+    if value.is_nan() {
+        "NaN".into()
+    } else if value.is_infinite() {
+        if value > 0.0 { "Infinity" } else { "-Infinity" }.into()
+    } else {
+        value.into()
+    }
+}
+
+/// Adds the `thread.id` and `process.pid` labels of the calling thread, and its
+/// `thread.name` if it has one.
+pub fn add_thread_labels(labels: &mut HashMap<String, String>) {
+    let thread = std::thread::current();
+    let id = format!("{:?}", thread.id());
+    let id = id.trim_start_matches("ThreadId(").trim_end_matches(')');
+    labels.insert("thread.id".into(), id.into());
+    if let Some(name) = thread.name() {
+        labels.insert("thread.name".into(), name.into());
+    }
+    labels.insert("process.pid".into(), std::process::id().to_string());
+}
+
+/// Names of fields, such as passwords or tokens, to hide or leave out of entries.
+/// Names are matched exactly but case-insensitively.
+#[derive(Clone, Debug, Default)]
+pub struct FieldFilter {
+    redacted: HashSet<String>,
+    dropped: HashSet<String>,
+}
+
+impl FieldFilter {
+    /// Replaces the values of the fields named `names` with `"***"`.
+    pub fn redact_fields(
+        mut self,
+        names: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Self {
+        self.redacted.extend(names.into_iter().map(|n| n.as_ref().to_lowercase()));
+        self
+    }
+
+    /// Leaves the fields named `names` out.
+    pub fn drop_fields(
+        mut self,
+        names: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Self {
+        self.dropped.extend(names.into_iter().map(|n| n.as_ref().to_lowercase()));
+        self
+    }
+
+    /// Redacts and drops the matching members of `fields`.
+    pub fn apply(&self, fields: &mut Map<String, Value>) {
+        if self.redacted.is_empty() && self.dropped.is_empty() {
+            return;
+        }
+        fields.retain(|name, value| {
+            let name = name.to_lowercase();
+            if self.redacted.contains(&name) {
+                *value = "***".into();
+            }
+            !self.dropped.contains(&name)
+        });
+    }
+}
+
+/// Tells, from its name and value, whether to drop a field, e.g. one whose value looks
+/// like a token, which a [`FieldFilter`] can't tell by name.
+pub type SkipFields = Box<dyn Fn(&str, &Value) -> bool + Send + Sync>;
+
+/// Cuts `message` and the strings in `fields` longer than `max_len` bytes down to
+/// `max_len` bytes followed by `…`, adding `truncated: true` to `fields` if any was.
+/// Cloud Logging rejects entries over its size limit as a whole.
+pub fn truncate_values(
+    message: &mut String,
+    fields: &mut Map<String, Value>,
+    max_len: usize,
+) {
+    let mut truncated = truncate_string(message, max_len);
+    for value in fields.values_mut() {
+        truncated |= truncate_value(value, max_len);
+    }
+    if truncated {
+        fields.insert("truncated".into(), true.into());
+    }
+}
+
+fn truncate_value(value: &mut Value, max_len: usize) -> bool {
+    match value {
+        Value::String(s) => truncate_string(s, max_len),
+        Value::Array(values) => {
+            values.iter_mut().fold(false, |t, v| truncate_value(v, max_len) | t)
+        }
+        Value::Object(members) => {
+            members.values_mut().fold(false, |t, v| truncate_value(v, max_len) | t)
+        }
+        _ => false,
+    }
+}
+
+fn truncate_string(s: &mut String, max_len: usize) -> bool {
+    if s.len() <= max_len {
+        return false;
+    }
+    let mut end = max_len;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    s.truncate(end);
+    s.push('…');
+    true
+}
+
+/// Caps the serialized size of the fields of an entry, dropping fields until they fit.
+/// Unlike [`truncate_values`], the fields that are kept are left whole.
+#[derive(Clone, Debug)]
+pub struct FieldBudget {
+    max_bytes: usize,
+    priorities: Vec<String>,
+}
+
+impl FieldBudget {
+    /// Keeps the fields within `max_bytes` bytes of JSON.
+    pub fn new(max_bytes: usize) -> Self {
+        FieldBudget { max_bytes, priorities: Vec::new() }
+    }
+
+    /// The fields to drop last, most important first: the fields named here are only
+    /// dropped once all others are, starting from the end of the list. The others are
+    /// dropped largest first.
+    pub fn priorities(
+        mut self,
+        names: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.priorities = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Drops members of `fields` until they fit in the budget, and lists the names of
+    /// those dropped under `dropped_fields`, which isn't counted against the budget.
+    pub fn apply(&self, fields: &mut Map<String, Value>) {
+        let size = |name: &String, value: &Value| {
+            serde_json::to_string(name).map_or(0, |n| n.len())
+                + serde_json::to_string(value).map_or(0, |v| v.len())
+                + 2
+        };
+        let mut total = 2 + fields.iter().map(|(n, v)| size(n, v)).sum::<usize>();
+        if total <= self.max_bytes {
+            return;
+        }
+        let priority = |name: &String| self.priorities.iter().position(|p| p == name);
+        let mut order: Vec<_> = fields
+            .iter()
+            .map(|(name, value)| (priority(name), size(name, value), name.clone()))
+            .collect();
+        // Fields without a priority first, largest first, then by decreasing priority.
+        order.sort_by(|a, b| match (a.0, b.0) {
+            (None, None) => b.1.cmp(&a.1),
+            (None, Some(_)) => std::cmp::Ordering::Less,
+            (Some(_), None) => std::cmp::Ordering::Greater,
+            (Some(a), Some(b)) => b.cmp(&a),
+        });
+        let mut dropped = Vec::new();
+        for (_, size, name) in order {
+            if total <= self.max_bytes {
+                break;
+            }
+            fields.remove(&name);
+            total -= size;
+            dropped.push(Value::from(name));
+        }
+        fields.insert("dropped_fields".into(), dropped.into());
+    }
+}
+
+/// Moves `label.`-prefixed fields into `labels` with the prefix stripped, overriding
+/// any label already present under the same key.
+pub fn extract_labels(
+    fields: &mut Map<String, Value>,
+    labels: &mut HashMap<String, String>,
+) {
+    let keys: Vec<String> =
+        fields.keys().filter(|k| k.starts_with(LABEL_PREFIX)).cloned().collect();
+    for key in keys {
+        let Some(value) = fields.remove(&key) else { continue };
+        let value = match value {
+            Value::String(s) => s,
+            other => other.to_string(),
+        };
+        labels.insert(key[LABEL_PREFIX.len()..].to_owned(), value);
+    }
+}
+
+const LABEL_PREFIX: &str = "label.";
+
+/// If `fields` has a `json_payload` string holding a JSON object, replaces it with
+/// the object's members and returns true. Anything else is left as a plain field.
+pub fn merge_json_payload(fields: &mut Map<String, Value>) -> bool {
+    let Some(Value::String(raw)) = fields.get(JSON_PAYLOAD) else { return false };
+    let Ok(Value::Object(payload)) = serde_json::from_str(raw) else { return false };
+    fields.remove(JSON_PAYLOAD);
+    fields.extend(payload);
+    true
+}
+
+const JSON_PAYLOAD: &str = "json_payload";
+
+/// Where an entry records the `target` of the event or record that produced it.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum TargetPlacement {
+    #[default]
+    Omit,
+    /// As the `target` label.
+    Label,
+    /// As a `target` member of the payload.
+    Field,
+}
+
+impl TargetPlacement {
+    /// Records `target` unless it is empty.
+    pub fn place(
+        self,
+        target: &str,
+        labels: &mut HashMap<String, String>,
+        fields: &mut Map<String, Value>,
+    ) {
+        if target.is_empty() {
+            return;
+        }
+        match self {
+            TargetPlacement::Omit => {}
+            TargetPlacement::Label => {
+                labels.insert("target".into(), target.into());
+            }
+            TargetPlacement::Field => {
+                fields.insert("target".into(), target.into());
+            }
+        }
+    }
+}
+
+/// Whether an entry with nothing but a message is written as JSON, which Cloud
+/// Logging stores as a `jsonPayload`, or as plain text, stored as a `textPayload`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PayloadFormat {
+    #[default]
+    Json,
+    /// Plain text for single-line INFO or DEFAULT messages without payload fields,
+    /// labels, trace, `httpRequest`, operation or Error Reporting members, which
+    /// plain text could not carry. Other special fields of such entries, e.g. their
+    /// `sourceLocation`, are lost.
+    TextIfUnstructured,
+}
+
+impl PayloadFormat {
+    /// The message to write as a plain text line instead of `entry`, if any.
+    pub fn text_payload<'e>(self, entry: &'e LogEntry) -> Option<&'e str> {
+        let plain = self == PayloadFormat::TextIfUnstructured
+            && matches!(entry.severity, "INFO" | "DEFAULT")
+            && entry.fields.is_empty()
+            && entry.labels.is_empty()
+            && entry.trace.is_none()
+            && entry.span_id.is_none()
+            && entry.http_request.is_none()
+            && entry.operation.is_none()
+            && entry.error_type.is_none()
+            && entry.service_context.is_none()
+            && !entry.message.is_empty()
+            && !entry.message.contains('\n');
+        plain.then_some(entry.message.as_str())
+    }
+}
+
+/// The names of GCP's `LogSeverity` enum.
+pub const LOG_SEVERITIES: [&str; 9] = [
+    "DEFAULT",
+    "DEBUG",
+    "INFO",
+    "NOTICE",
+    "WARNING",
+    "ERROR",
+    "CRITICAL",
+    "ALERT",
+    "EMERGENCY",
+];
+
+/// Returns the `LogSeverity` named `name`, if there is one.
+pub fn parse_gcp_severity(name: &str) -> Option<&'static str> {
+    LOG_SEVERITIES.into_iter().find(|s| *s == name)
+}
+
+/// The number of the `LogSeverity` named `name`: 0 for DEFAULT, 100 for DEBUG and so
+/// on up to 800 for EMERGENCY.
+pub fn severity_number(name: &str) -> Option<u32> {
+    LOG_SEVERITIES.iter().position(|s| *s == name).map(|i| i as u32 * 100)
+}
+
+/// Returns the more severe of two `LogSeverity` names. Names that aren't in
+/// [`LOG_SEVERITIES`] rank below `DEFAULT`.
+pub fn max_severity(a: &'static str, b: &'static str) -> &'static str {
+    let rank = |s| LOG_SEVERITIES.iter().position(|l| *l == s);
+    if rank(b) > rank(a) { b } else { a }
+}
+
+/// Log levels that have a GCP `LogSeverity` counterpart.
+pub trait GcpSeverity {
+    fn gcp_severity(&self) -> &'static str;
+}
+
+impl GcpSeverity for tracing::Level {
+    fn gcp_severity(&self) -> &'static str {
+        match *self {
+            tracing::Level::TRACE | tracing::Level::DEBUG => "DEBUG",
+            tracing::Level::INFO => "INFO",
+            tracing::Level::WARN => "WARNING",
+            _ => "ERROR",
+        }
+    }
+}
+
+impl GcpSeverity for log::Level {
+    fn gcp_severity(&self) -> &'static str {
+        match self {
+            log::Level::Trace | log::Level::Debug => "DEBUG",
+            log::Level::Info => "INFO",
+            log::Level::Warn => "WARNING",
+            log::Level::Error => "ERROR",
+        }
+    }
+}
+
+/// Maps a `tracing` or `log` level to a GCP `LogSeverity` name. GCP has no
+/// `TRACE`, so it is reported as `DEBUG`.
+pub fn level_to_gcp_severity(level: &impl GcpSeverity) -> &'static str {
+    level.gcp_severity()
+}
+
+pub fn get_gcp_project_id() -> String {
+    "PROJECT_ID_123".into()
+}
+
+/// How long [`detect_project_id`] waits for the metadata server. Off GCP there is
+/// none, and startup shouldn't hang on it.
+#[cfg(feature = "metadata")]
+pub const METADATA_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Finds the project the process runs in: the GCE/Cloud Run/GKE metadata server
+/// when the `metadata` feature is enabled, then the `GOOGLE_CLOUD_PROJECT` env var.
+///
+/// Gives up on the metadata server after [`METADATA_TIMEOUT`], warning on stderr the
+/// first time it can't be reached.
+pub async fn detect_project_id() -> Option<String> {
+    #[cfg(feature = "metadata")]
+    if let Some(project_id) = query_metadata_project_id(METADATA_PROJECT_ID_URL).await {
+        return Some(project_id);
+    }
+    project_id_from_env()
+}
+
+/// Whether the process runs on Cloud Run, as `K_SERVICE` tells, or says which project
+/// it logs to with `GOOGLE_CLOUD_PROJECT`: then entries should be Cloud Logging JSON,
+/// and otherwise human-readable.
+pub fn running_on_gcp() -> bool {
+    ["K_SERVICE", "GOOGLE_CLOUD_PROJECT"]
+        .into_iter()
+        .any(|var| std::env::var_os(var).is_some_and(|v| !v.is_empty()))
+}
+
+/// The version of the running service, for correlating entries to a release: the
+/// `SERVICE_VERSION` env var. Applications fall back to their own
+/// `env!("CARGO_PKG_VERSION")`, which only exists at build time.
+pub fn service_version_from_env() -> Option<String> {
+    std::env::var("SERVICE_VERSION").ok().filter(|v| !v.is_empty())
+}
+
+/// Reads the `GOOGLE_CLOUD_PROJECT` env var.
+pub fn project_id_from_env() -> Option<String> {
+    std::env::var("GOOGLE_CLOUD_PROJECT").ok().filter(|p| !p.is_empty())
+}
+
+/// Returned by the builders of the backends when no project id was configured or
+/// detected.
+#[derive(Debug)]
+pub struct MissingProjectId;
+
+impl fmt::Display for MissingProjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("no GCP project id was configured or detected")
+    }
+}
+
+impl std::error::Error for MissingProjectId {}
+
+#[cfg(feature = "metadata")]
+const METADATA_PROJECT_ID_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/project/project-id";
+
+/// The project id the metadata server at `url` answers, within [`METADATA_TIMEOUT`].
+#[cfg(feature = "metadata")]
+async fn query_metadata_project_id(url: &str) -> Option<String> {
+    static WARN_UNREACHABLE: Once = Once::new();
+    let client = reqwest::Client::builder().timeout(METADATA_TIMEOUT).build().ok()?;
+    let request = client.get(url).header("Metadata-Flavor", "Google");
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(e) => {
+            WARN_UNREACHABLE.call_once(|| {
+                eprintln!(
+                    "metadata server unreachable, using the configured project: {e}"
+                );
+            });
+            return None;
+        }
+    };
+    let project_id = response.error_for_status().ok()?.text().await.ok()?;
+    Some(project_id.trim().to_owned()).filter(|p| !p.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn maps_levels_to_gcp_severities() {
+        let tracing_levels = [
+            (tracing::Level::TRACE, "DEBUG"),
+            (tracing::Level::DEBUG, "DEBUG"),
+            (tracing::Level::INFO, "INFO"),
+            (tracing::Level::WARN, "WARNING"),
+            (tracing::Level::ERROR, "ERROR"),
+        ];
+        for (level, severity) in tracing_levels {
+            assert_eq!(level_to_gcp_severity(&level), severity, "{level}");
+        }
+        let log_levels = [
+            (log::Level::Trace, "DEBUG"),
+            (log::Level::Debug, "DEBUG"),
+            (log::Level::Info, "INFO"),
+            (log::Level::Warn, "WARNING"),
+            (log::Level::Error, "ERROR"),
+        ];
+        for (level, severity) in log_levels {
+            assert_eq!(level_to_gcp_severity(&level), severity, "{level}");
+        }
+    }
+
+    #[test]
+    fn parses_cloud_trace_context_headers() {
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        assert_eq!(
+            parse_cloud_trace_context(&format!("{trace_id}/1234;o=1")),
+            Some((trace_id.to_owned(), Some(1234), Some(true)))
+        );
+        assert_eq!(
+            parse_cloud_trace_context(&format!("{trace_id}/1234;o=0")),
+            Some((trace_id.to_owned(), Some(1234), Some(false)))
+        );
+        assert_eq!(
+            parse_cloud_trace_context(&format!("{trace_id}/1234")),
+            Some((trace_id.to_owned(), Some(1234), None))
+        );
+        assert_eq!(
+            parse_cloud_trace_context(trace_id),
+            Some((trace_id.to_owned(), None, None))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_cloud_trace_context_headers() {
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        for header in [
+            "",
+            "/1234;o=1",
+            &format!("{trace_id}/abc;o=1"),
+            &format!("{trace_id}/1234;o=2"),
+        ] {
+            assert_eq!(parse_cloud_trace_context(header), None, "{header:?}");
+        }
+    }
+
+    /// Writes a member, then fails.
+    struct FailingValue;
+
+    impl Serialize for FailingValue {
+        fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            use serde::ser::{Error, SerializeMap};
+            let mut map = s.serialize_map(None)?;
+            map.serialize_entry("partial", "output")?;
+            Err(S::Error::custom("failing value"))
+        }
+    }
+
+    #[test]
+    fn reuses_the_line_buffer_cleared_after_errors() {
+        let entry = |message: &str| {
+            LogEntry::new("INFO", message.into(), Timestamp::Rfc3339("t".into()))
+        };
+        let (_, first_buffer) =
+            with_serialized_line(&entry("first entry"), |line| ((), line.as_ptr()))
+                .unwrap();
+        let error = with_serialized(&FailingValue, false, |_| ()).unwrap_err();
+        assert!(error.to_string().contains("failing value"));
+        let (line, buffer) =
+            with_serialized_line(&entry("after"), |line| (line.to_vec(), line.as_ptr()))
+                .unwrap();
+        assert_eq!(
+            line,
+            b"{\"severity\":\"INFO\",\"message\":\"after\",\"time\":\"t\"}\n"
+        );
+        assert_eq!(buffer, first_buffer, "the buffer is reused");
+    }
+
+    #[test]
+    fn writes_multiline_messages_on_a_single_line() {
+        let message = "first line\nsecond line\r\n";
+        let entry = LogEntry::new("INFO", message.into(), Timestamp::Rfc3339("t".into()));
+        let line = with_serialized_line(&entry, <[u8]>::to_vec).unwrap();
+        let line = String::from_utf8(line).unwrap();
+        assert_eq!(line.lines().count(), 1, "{line}");
+        assert!(line.ends_with("}\n"));
+        let parsed: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(parsed["message"], message);
+    }
+
+    #[test]
+    fn normalizes_trace_ids() {
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        assert_eq!(normalize_trace_id(trace_id).as_deref(), Some(trace_id));
+        assert_eq!(
+            normalize_trace_id("4BF92F3577B34DA6A3CE929D0E0E4736").as_deref(),
+            Some(trace_id)
+        );
+        assert_eq!(normalize_trace_id("4bf92f3577b34da6"), None);
+        assert_eq!(normalize_trace_id("4bf92f3577b34da6a3ce929d0e0e473z"), None);
+        assert_eq!(
+            format_trace("test-project", "4BF92F3577B34DA6A3CE929D0E0E4736").as_deref(),
+            Some("projects/test-project/traces/4bf92f3577b34da6a3ce929d0e0e4736")
+        );
+        assert_eq!(format_trace("test-project", "456"), None);
+    }
+
+    #[test]
+    fn extracts_the_trace_context_of_the_first_header_present() {
+        let extract = |headers: &[(&'static str, &'static str)]| {
+            let headers: HashMap<_, _> = headers.iter().copied().collect();
+            TraceContextExtractor::default().extract(|name| headers.get(name).copied())
+        };
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let from_traceparent =
+            Some((trace_id.to_owned(), Some("00f067aa0ba902b7".to_owned()), Some(true)));
+        assert_eq!(extract(&[("traceparent", traceparent)]), from_traceparent);
+        assert_eq!(
+            extract(&[("x-cloud-trace-context", "/1"), ("traceparent", traceparent)]),
+            from_traceparent,
+            "a malformed first header is skipped"
+        );
+        assert_eq!(
+            extract(&[
+                ("x-cloud-trace-context", "4bf92f3577b34da6a3ce929d0e0e4736/1;o=0"),
+                ("traceparent", traceparent),
+            ]),
+            Some((trace_id.to_owned(), Some("0000000000000001".to_owned()), Some(false)))
+        );
+        assert_eq!(extract(&[]), None);
+    }
+
+    #[test]
+    fn formats_the_same_trace_with_a_precomputed_prefix() {
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        let prefix = trace_prefix("test-project");
+        assert_eq!(prefix, "projects/test-project/traces/");
+        let trace = format_trace_with_prefix(&prefix, trace_id).unwrap();
+        assert_eq!(trace, format!("projects/test-project/traces/{trace_id}"));
+        assert_eq!(Some(trace), format_trace("test-project", trace_id));
+    }
+
+    #[test]
+    fn truncates_values_on_a_char_boundary() {
+        let mut message = "héllo".to_string();
+        let mut fields = Map::new();
+        truncate_values(&mut message, &mut fields, 2);
+        assert_eq!(message, "h…");
+        assert_eq!(fields["truncated"], true);
+    }
+
+    #[test]
+    fn drops_fields_over_the_budget_in_priority_order() {
+        let fields = || {
+            let mut fields = Map::new();
+            fields.insert("user".into(), "alice".into());
+            fields.insert("request_id".into(), "r1".into());
+            fields.insert("body".into(), "x".repeat(100).into());
+            fields.insert("extra".into(), "y".repeat(50).into());
+            fields.insert("n".into(), 1.into());
+            fields
+        };
+        let budget =
+            |max_bytes| FieldBudget::new(max_bytes).priorities(["user", "request_id"]);
+
+        let mut within = fields();
+        budget(1000).apply(&mut within);
+        assert_eq!(within, fields());
+
+        let mut largest_dropped = fields();
+        budget(60).apply(&mut largest_dropped);
+        assert_eq!(
+            Value::from(largest_dropped),
+            json!({"user": "alice", "request_id": "r1", "n": 1, "dropped_fields": ["body", "extra"]})
+        );
+
+        let mut prioritized_kept = fields();
+        budget(20).apply(&mut prioritized_kept);
+        assert_eq!(
+            Value::from(prioritized_kept),
+            json!({"user": "alice", "dropped_fields": ["body", "extra", "n", "request_id"]})
+        );
+    }
+
+    #[test]
+    fn writes_entries_to_any_writer() {
+        let entry = || {
+            let time = Timestamp::Rfc3339("2024-05-01T12:00:00.000Z".into());
+            LogEntry::new("INFO", "hello".into(), time)
+        };
+        let mut bytes = Vec::new();
+        write_entry(&mut bytes, &mut entry(), WriteOptions::default()).unwrap();
+        assert_eq!(
+            bytes,
+            b"{\"severity\":\"INFO\",\"message\":\"hello\",\"time\":\"2024-05-01T12:00:00.000Z\"}\n"
+        );
+
+        let options = WriteOptions {
+            numeric_severity: true,
+            record_delimiter: b'\0',
+            ..WriteOptions::default()
+        };
+        let mut bytes = Vec::new();
+        write_entry(&mut bytes, &mut entry(), options).unwrap();
+        assert_eq!(
+            bytes,
+            b"{\"message\":\"hello\",\"time\":\"2024-05-01T12:00:00.000Z\",\"severity\":200}\0"
+        );
+
+        let options = WriteOptions {
+            payload_format: PayloadFormat::TextIfUnstructured,
+            ..WriteOptions::default()
+        };
+        let mut bytes = Vec::new();
+        write_entry(&mut bytes, &mut entry(), options).unwrap();
+        assert_eq!(bytes, b"hello\n");
+    }
+
+    #[cfg(all(feature = "metadata", feature = "tokio"))]
+    #[tokio::test]
+    async fn gives_up_on_a_metadata_server_that_never_responds() {
+        // Connections are accepted by the backlog, but no response is ever written.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/project-id", listener.local_addr().unwrap());
+        let started = std::time::Instant::now();
+        assert_eq!(query_metadata_project_id(&url).await, None);
+        let waited = started.elapsed();
+        assert!(waited >= METADATA_TIMEOUT, "{waited:?}");
+        assert!(waited < METADATA_TIMEOUT * 4, "{waited:?}");
+    }
+
+    #[test]
+    fn reads_the_service_version_from_service_version_only() {
+        // SAFETY: no other test reads or writes `SERVICE_VERSION`.
+        unsafe { std::env::set_var("SERVICE_VERSION", "1.2.3") };
+        assert_eq!(service_version_from_env().as_deref(), Some("1.2.3"));
+        // `cargo test` sets `CARGO_PKG_VERSION`, which deployed binaries don't have.
+        unsafe { std::env::remove_var("SERVICE_VERSION") };
+        assert_eq!(service_version_from_env(), None);
+    }
+}
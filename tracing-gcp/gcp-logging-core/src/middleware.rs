@@ -0,0 +1,70 @@
+//! A `tower` middleware that puts each request in the trace given by its trace
+//! context headers.
+//!
+//! With the `tonic` feature, it also wraps gRPC services: gRPC metadata travel as
+//! HTTP/2 headers, so `Server::builder().add_service(TraceContextLayer::default()
+//! .layer(GreeterServer::new(greeter)))` runs each call in the trace of its
+//! `x-cloud-trace-context` or `traceparent` metadata. A `tonic` interceptor can't,
+//! as it maps the request but not the future handling it.
+
+use crate::trace_scope::scope_trace_context;
+use crate::{TraceContextExtractor, TraceScoped};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+/// Runs requests in their trace, like [`with_trace_id`](crate::with_trace_id) does,
+/// also recording the span id and sampled flag their header may carry on the
+/// `trace_id` span. The default reads the headers of [`TraceContextExtractor::default`].
+#[derive(Clone, Debug, Default)]
+pub struct TraceContextLayer {
+    extractor: Arc<TraceContextExtractor>,
+}
+
+impl TraceContextLayer {
+    pub fn new(extractor: TraceContextExtractor) -> Self {
+        TraceContextLayer { extractor: Arc::new(extractor) }
+    }
+}
+
+impl<S> Layer<S> for TraceContextLayer {
+    type Service = TraceContextService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TraceContextService { inner, extractor: self.extractor.clone() }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TraceContextService<S> {
+    inner: S,
+    extractor: Arc<TraceContextExtractor>,
+}
+
+impl<S, B> Service<http::Request<B>> for TraceContextService<S>
+where
+    S: Service<http::Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = TraceScoped<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let context =
+            self.extractor.extract(|name| req.headers().get(name)?.to_str().ok());
+        scope_trace_context(context, self.inner.call(req))
+    }
+}
+
+/// Lets `tonic` route to wrapped services, as it does to the services its code
+/// generator makes.
+#[cfg(feature = "tonic")]
+impl<S: tonic::server::NamedService> tonic::server::NamedService
+    for TraceContextService<S>
+{
+    const NAME: &'static str = S::NAME;
+}
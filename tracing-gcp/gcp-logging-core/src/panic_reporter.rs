@@ -0,0 +1,78 @@
+//! Reports panics as CRITICAL entries that Error Reporting can group.
+
+use crate::{
+    Clock, LogEntry, REPORTED_ERROR_EVENT_TYPE, SystemClock, Timestamp, TimestampFormat,
+    format_trace, with_serialized_line,
+};
+use std::backtrace::Backtrace;
+use std::io::Write;
+use std::panic::PanicHookInfo;
+
+/// Installs a panic hook that writes each panic, with its backtrace formatted like a
+/// Java stack trace, as a CRITICAL entry to the writers returned by `make_writer`.
+/// The previously installed hook still runs afterwards.
+pub fn install_panic_reporter<W, M>(project_id: impl Into<String>, make_writer: M)
+where
+    W: Write,
+    M: Fn() -> W + Send + Sync + 'static,
+{
+    let project_id = project_id.into();
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let backtrace = Backtrace::force_capture();
+        // Without tokio there is no task to hold a trace id.
+        #[cfg(feature = "tokio")]
+        let trace_id = crate::TASK_LOCAL_TRACE_ID.try_with(|t| t.clone()).ok().flatten();
+        #[cfg(not(feature = "tokio"))]
+        let trace_id: Option<String> = None;
+        let mut entry = LogEntry::new(
+            "CRITICAL",
+            format_panic(info, &backtrace),
+            Timestamp::new(SystemClock.now(), TimestampFormat::default()),
+        );
+        entry.trace = trace_id.and_then(|t| format_trace(&project_id, &t));
+        entry.error_type = Some(REPORTED_ERROR_EVENT_TYPE);
+        let _ = with_serialized_line(&entry, |line| make_writer().write_all(line));
+        previous(info);
+    }));
+}
+
+fn format_panic(info: &PanicHookInfo<'_>, backtrace: &Backtrace) -> String {
+    let payload = if let Some(s) = info.payload().downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = info.payload().downcast_ref::<String>() {
+        s.as_str()
+    } else {
+        "Box<dyn Any>"
+    };
+    let mut message = format!("panic: {payload}");
+    if let Some(location) = info.location() {
+        message.push_str(&format!(" at {}:{}", location.file(), location.line()));
+    }
+    for (symbol, location) in frames(&backtrace.to_string()) {
+        let location = location.unwrap_or_else(|| "Unknown Source".into());
+        message.push_str(&format!("\n\tat {symbol}({location})"));
+    }
+    message
+}
+
+/// Splits the `Display` output of a [`Backtrace`] into symbols and `file:line`s.
+fn frames(backtrace: &str) -> Vec<(String, Option<String>)> {
+    let mut frames: Vec<(String, Option<String>)> = Vec::new();
+    for line in backtrace.lines().map(str::trim) {
+        if let Some(location) = line.strip_prefix("at ") {
+            let location = match location.rsplit_once(':') {
+                Some((file_line, column)) if column.parse::<u32>().is_ok() => file_line,
+                _ => location,
+            };
+            if let Some(frame) = frames.last_mut() {
+                frame.1 = Some(location.to_owned());
+            }
+        } else if let Some((index, symbol)) = line.split_once(": ")
+            && index.parse::<usize>().is_ok()
+        {
+            frames.push((symbol.to_owned(), None));
+        }
+    }
+    frames
+}
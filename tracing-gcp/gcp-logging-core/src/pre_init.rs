@@ -0,0 +1,67 @@
+//! A writer that holds on to the entries logged before logging is set up.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct Buffered {
+    lines: VecDeque<Vec<u8>>,
+    dropped: usize,
+}
+
+/// Keeps the last `capacity` entries written to it in memory, until
+/// [`replay`](PreInitBuffer::replay) writes them to the real writer. Meant for the
+/// entries logged during startup, e.g. while loading the configuration, before the
+/// real writer can be set up.
+///
+/// Clones share the same entries.
+#[derive(Clone)]
+pub struct PreInitBuffer {
+    buffered: Arc<Mutex<Buffered>>,
+    capacity: usize,
+}
+
+impl PreInitBuffer {
+    pub fn new(capacity: usize) -> Self {
+        PreInitBuffer { buffered: Arc::default(), capacity }
+    }
+
+    /// Writes the buffered entries to `writer`, oldest first, and empties the buffer.
+    /// Warns on stderr when older entries were dropped to stay within the capacity.
+    pub fn replay(&self, mut writer: impl Write) -> io::Result<()> {
+        let Buffered { lines, dropped } =
+            std::mem::take(&mut *self.buffered.lock().unwrap());
+        if dropped > 0 {
+            eprintln!("dropped {dropped} log entries logged before logging was set up");
+        }
+        for line in lines {
+            writer.write_all(&line)?;
+        }
+        writer.flush()
+    }
+}
+
+impl Write for PreInitBuffer {
+    /// Expects a whole entry per call, as [`with_serialized_line`] writes them. Once
+    /// full, each entry replaces the oldest one.
+    ///
+    /// [`with_serialized_line`]: crate::with_serialized_line
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut buffered = self.buffered.lock().unwrap();
+        if self.capacity == 0 {
+            buffered.dropped += 1;
+            return Ok(buf.len());
+        }
+        if buffered.lines.len() == self.capacity {
+            buffered.lines.pop_front();
+            buffered.dropped += 1;
+        }
+        buffered.lines.push_back(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
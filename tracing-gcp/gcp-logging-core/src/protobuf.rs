@@ -0,0 +1,338 @@
+//! Encoding entries as the `google.logging.v2.LogEntry` protobuf message.
+//!
+//! The messages are declared with `prost` derives rather than generated from the
+//! `.proto` files, so the feature needs no `protoc`. They keep the field numbers of
+//! `google/logging/v2/log_entry.proto` and `google/logging/type/http_request.proto`,
+//! and leave out the fields entries never set.
+
+use crate::{HttpRequest, LogEntry, SourceLocation, Timestamp, severity_number};
+use chrono::DateTime;
+use prost::Message;
+use prost_types::{ListValue, Struct, Value as ProtoValue, value::Kind};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+
+/// `google.logging.v2.LogEntry`.
+#[derive(Clone, PartialEq, Message)]
+struct LogEntryProto {
+    #[prost(string, tag = "12")]
+    log_name: String,
+    #[prost(message, optional, tag = "8")]
+    resource: Option<MonitoredResourceProto>,
+    #[prost(oneof = "Payload", tags = "3, 6")]
+    payload: Option<Payload>,
+    #[prost(message, optional, tag = "9")]
+    timestamp: Option<prost_types::Timestamp>,
+    /// A `google.logging.type.LogSeverity`.
+    #[prost(int32, tag = "10")]
+    severity: i32,
+    #[prost(string, tag = "4")]
+    insert_id: String,
+    #[prost(message, optional, tag = "7")]
+    http_request: Option<HttpRequestProto>,
+    #[prost(btree_map = "string, string", tag = "11")]
+    labels: BTreeMap<String, String>,
+    #[prost(message, optional, tag = "15")]
+    operation: Option<LogEntryOperation>,
+    #[prost(string, tag = "22")]
+    trace: String,
+    #[prost(string, tag = "27")]
+    span_id: String,
+    #[prost(bool, tag = "30")]
+    trace_sampled: bool,
+    #[prost(message, optional, tag = "23")]
+    source_location: Option<LogEntrySourceLocation>,
+}
+
+/// The `payload` oneof of `google.logging.v2.LogEntry`.
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum Payload {
+    #[prost(string, tag = "3")]
+    TextPayload(String),
+    #[prost(message, tag = "6")]
+    JsonPayload(Struct),
+}
+
+/// `google.api.MonitoredResource`.
+#[derive(Clone, PartialEq, Message)]
+struct MonitoredResourceProto {
+    #[prost(string, tag = "1")]
+    r#type: String,
+    #[prost(btree_map = "string, string", tag = "2")]
+    labels: BTreeMap<String, String>,
+}
+
+/// `google.logging.type.HttpRequest`.
+#[derive(Clone, PartialEq, Message)]
+struct HttpRequestProto {
+    #[prost(string, tag = "1")]
+    request_method: String,
+    #[prost(string, tag = "2")]
+    request_url: String,
+    #[prost(int64, tag = "3")]
+    request_size: i64,
+    #[prost(int32, tag = "4")]
+    status: i32,
+    #[prost(int64, tag = "5")]
+    response_size: i64,
+    #[prost(string, tag = "6")]
+    user_agent: String,
+    #[prost(string, tag = "7")]
+    remote_ip: String,
+    #[prost(string, tag = "13")]
+    server_ip: String,
+    #[prost(string, tag = "8")]
+    referer: String,
+    #[prost(message, optional, tag = "14")]
+    latency: Option<prost_types::Duration>,
+    #[prost(string, tag = "15")]
+    protocol: String,
+}
+
+/// `google.logging.v2.LogEntryOperation`.
+#[derive(Clone, PartialEq, Message)]
+struct LogEntryOperation {
+    #[prost(string, tag = "1")]
+    id: String,
+    #[prost(string, tag = "2")]
+    producer: String,
+    #[prost(bool, tag = "3")]
+    first: bool,
+    #[prost(bool, tag = "4")]
+    last: bool,
+}
+
+/// `google.logging.v2.LogEntrySourceLocation`.
+#[derive(Clone, PartialEq, Message)]
+struct LogEntrySourceLocation {
+    #[prost(string, tag = "1")]
+    file: String,
+    #[prost(int64, tag = "2")]
+    line: i64,
+    #[prost(string, tag = "3")]
+    function: String,
+}
+
+impl LogEntry<'_> {
+    /// Encodes the entry as a `google.logging.v2.LogEntry`. The message and the other
+    /// fields make up its `jsonPayload`, as they do when Cloud Logging parses the JSON
+    /// line.
+    pub fn to_protobuf(&self) -> Vec<u8> {
+        self.to_proto().encode_to_vec()
+    }
+
+    /// Like [`to_protobuf`](Self::to_protobuf), prefixed with the varint length of the
+    /// message so that a stream of entries can be split again.
+    pub fn to_length_delimited_protobuf(&self) -> Vec<u8> {
+        self.to_proto().encode_length_delimited_to_vec()
+    }
+
+    fn to_proto(&self) -> LogEntryProto {
+        let payload = self.json_payload();
+        LogEntryProto {
+            log_name: self.log_name.unwrap_or_default().to_owned(),
+            resource: self.resource.map(|resource| MonitoredResourceProto {
+                r#type: resource.resource_type.clone(),
+                labels: sorted(&resource.labels),
+            }),
+            payload: (!payload.is_empty())
+                .then(|| Payload::JsonPayload(to_struct(payload))),
+            timestamp: seconds_nanos(&self.time)
+                .map(|(seconds, nanos)| prost_types::Timestamp { seconds, nanos }),
+            severity: severity_number(self.severity).unwrap_or_default() as i32,
+            insert_id: self.insert_id.clone().unwrap_or_default(),
+            http_request: self.http_request.as_ref().map(to_http_request),
+            labels: sorted(&self.labels),
+            operation: self.operation.as_ref().map(|operation| LogEntryOperation {
+                id: operation.id.clone(),
+                producer: operation.producer.clone().unwrap_or_default(),
+                first: operation.first,
+                last: operation.last,
+            }),
+            trace: self.trace.clone().unwrap_or_default(),
+            span_id: self.span_id.clone().unwrap_or_default(),
+            trace_sampled: self.trace_sampled.unwrap_or_default(),
+            source_location: self.source_location.as_ref().map(to_source_location),
+        }
+    }
+
+    /// The members Cloud Logging moves into `jsonPayload` when reading the JSON line.
+    fn json_payload(&self) -> Map<String, Value> {
+        let mut payload = self.fields.clone();
+        if !self.message.is_empty() {
+            payload.insert("message".into(), self.message.clone().into());
+        }
+        if let Some(error_type) = self.error_type {
+            payload.insert("@type".into(), error_type.into());
+        }
+        if let Some(service_context) = self.service_context {
+            let service_context = serde_json::to_value(service_context)
+                .expect("service contexts serialize to JSON");
+            payload.insert("serviceContext".into(), service_context);
+        }
+        payload
+    }
+}
+
+fn seconds_nanos(time: &Timestamp) -> Option<(i64, i32)> {
+    match time {
+        Timestamp::Rfc3339(time) => {
+            let time = DateTime::parse_from_rfc3339(time).ok()?;
+            Some((time.timestamp(), time.timestamp_subsec_nanos() as i32))
+        }
+        Timestamp::SecondsNanos { seconds, nanos } => Some((*seconds, *nanos as i32)),
+        Timestamp::Moved => None,
+    }
+}
+
+fn to_http_request(r: &HttpRequest) -> HttpRequestProto {
+    let string = |value: &Option<String>| value.clone().unwrap_or_default();
+    let latency = r.latency.as_deref().and_then(|l| l.strip_suffix('s')?.parse().ok());
+    HttpRequestProto {
+        request_method: string(&r.request_method),
+        request_url: string(&r.request_url),
+        request_size: r.request_size.unwrap_or_default() as i64,
+        status: r.status.unwrap_or_default().into(),
+        response_size: r.response_size.unwrap_or_default() as i64,
+        user_agent: string(&r.user_agent),
+        remote_ip: string(&r.remote_ip),
+        server_ip: string(&r.server_ip),
+        referer: string(&r.referer),
+        latency: latency.map(|latency| {
+            let latency = std::time::Duration::from_secs_f64(latency);
+            prost_types::Duration {
+                seconds: latency.as_secs() as i64,
+                nanos: latency.subsec_nanos() as i32,
+            }
+        }),
+        protocol: string(&r.protocol),
+    }
+}
+
+fn to_source_location(location: &SourceLocation) -> LogEntrySourceLocation {
+    LogEntrySourceLocation {
+        file: location.file.unwrap_or_default().to_owned(),
+        line: location.line.unwrap_or_default().into(),
+        function: location.function.unwrap_or_default().to_owned(),
+    }
+}
+
+/// A `map<string, string>`, sorted by key so that identical entries encode the same.
+fn sorted(map: &HashMap<String, String>) -> BTreeMap<String, String> {
+    map.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+}
+
+/// A `google.protobuf.Struct`.
+fn to_struct(members: Map<String, Value>) -> Struct {
+    Struct { fields: members.into_iter().map(|(k, v)| (k, to_value(v))).collect() }
+}
+
+/// A `google.protobuf.Value`.
+fn to_value(value: Value) -> ProtoValue {
+    let kind = match value {
+        Value::Null => Kind::NullValue(0),
+        Value::Number(n) => Kind::NumberValue(n.as_f64().unwrap_or_default()),
+        Value::String(s) => Kind::StringValue(s),
+        Value::Bool(b) => Kind::BoolValue(b),
+        Value::Object(members) => Kind::StructValue(to_struct(members)),
+        Value::Array(values) => Kind::ListValue(ListValue {
+            values: values.into_iter().map(to_value).collect(),
+        }),
+    };
+    ProtoValue { kind: Some(kind) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Operation;
+
+    #[test]
+    fn decodes_the_severity_message_trace_and_time() {
+        let trace = "projects/p/traces/4bf92f3577b34da6a3ce929d0e0e4736";
+        let entry = LogEntry::builder("WARNING", "disk almost full")
+            .time(Timestamp::SecondsNanos { seconds: -1, nanos: 500 })
+            .trace(trace)
+            .field("free_bytes", 1024)
+            .field("disks", serde_json::json!(["sda", null]))
+            .build();
+        let decoded = LogEntryProto::decode(&entry.to_protobuf()[..]).unwrap();
+
+        assert_eq!(decoded.severity, 400);
+        assert_eq!(decoded.trace, trace);
+        assert_eq!(
+            decoded.timestamp,
+            Some(prost_types::Timestamp { seconds: -1, nanos: 500 })
+        );
+        let Some(Payload::JsonPayload(payload)) = decoded.payload else {
+            panic!("{:?}", decoded.payload)
+        };
+        let kinds: Vec<_> = payload.fields.iter().map(|(k, v)| (k, &v.kind)).collect();
+        let string = |s: &str| ProtoValue { kind: Some(Kind::StringValue(s.into())) };
+        let null = ProtoValue { kind: Some(Kind::NullValue(0)) };
+        assert_eq!(
+            kinds,
+            [
+                (
+                    &"disks".to_owned(),
+                    &Some(Kind::ListValue(ListValue {
+                        values: vec![string("sda"), null]
+                    }))
+                ),
+                (&"free_bytes".to_owned(), &Some(Kind::NumberValue(1024.0))),
+                (
+                    &"message".to_owned(),
+                    &Some(Kind::StringValue("disk almost full".into()))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_the_http_request_operation_and_labels() {
+        let http_request = HttpRequest {
+            request_method: Some("GET".into()),
+            status: Some(404),
+            latency: Some("0.25s".into()),
+            ..HttpRequest::default()
+        };
+        let mut entry = LogEntry::builder("INFO", "").build();
+        entry.http_request = Some(http_request);
+        entry.operation = Some(Operation {
+            id: "op-1".into(),
+            producer: Some("worker".into()),
+            first: true,
+            last: false,
+        });
+        entry.labels =
+            HashMap::from([("b".into(), "2".into()), ("a".into(), "1".into())]);
+        let decoded = LogEntryProto::decode(&entry.to_protobuf()[..]).unwrap();
+
+        let http_request = decoded.http_request.unwrap();
+        assert_eq!(http_request.request_method, "GET");
+        assert_eq!(http_request.status, 404);
+        assert_eq!(
+            http_request.latency,
+            Some(prost_types::Duration { seconds: 0, nanos: 250_000_000 })
+        );
+        assert_eq!(
+            decoded.operation,
+            Some(LogEntryOperation {
+                id: "op-1".into(),
+                producer: "worker".into(),
+                first: true,
+                last: false,
+            })
+        );
+        assert_eq!(decoded.labels.keys().collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(decoded.payload, None);
+    }
+
+    #[test]
+    fn prefixes_the_entry_with_its_length() {
+        let entry = LogEntry::builder("INFO", "hello").build();
+        let delimited = entry.to_length_delimited_protobuf();
+        let decoded = LogEntryProto::decode_length_delimited(&delimited[..]).unwrap();
+        assert_eq!(decoded, LogEntryProto::decode(&entry.to_protobuf()[..]).unwrap());
+    }
+}
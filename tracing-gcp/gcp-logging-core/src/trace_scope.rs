@@ -0,0 +1,79 @@
+//! Carrying a trace id through async tasks.
+
+use crate::{TraceContext, normalize_trace_id};
+use tokio::task::futures::TaskLocalFuture;
+use tracing::field::Empty;
+use tracing::instrument::Instrumented;
+use tracing::{Instrument, Span, info_span};
+
+tokio::task_local! {
+    /// The trace id `GcpJsonEncoder` puts on entries logged by the current task.
+    pub static TASK_LOCAL_TRACE_ID: Option<String>;
+}
+
+tokio::task_local! {
+    /// The correlation id `GcpJsonEncoder` puts in the labels of entries logged by the
+    /// current task.
+    pub static TASK_LOCAL_CORRELATION_ID: String;
+}
+
+/// A future running in the trace set up by [`with_trace_id`].
+pub type TraceScoped<F> = Instrumented<TaskLocalFuture<Option<String>, F>>;
+
+/// Runs `fut` in the trace `trace_id` for both backends: inside a `trace_id` span,
+/// which `GcpLayer` reads, and with [`TASK_LOCAL_TRACE_ID`] set, which
+/// `GcpJsonEncoder` reads.
+///
+/// When `tracing` events are forwarded to `log` (or the reverse) both backends see
+/// the same trace id, so an entry is never correlated to two different traces.
+pub fn with_trace_id<F: Future>(trace_id: impl Into<String>, fut: F) -> TraceScoped<F> {
+    scope_trace_context(Some((trace_id.into(), None, None)), fut)
+}
+
+/// Like [`with_trace_id`], also recording the span id and sampled flag of `context`
+/// on the `trace_id` span. Without a context, `fut` runs in no trace.
+pub(crate) fn scope_trace_context<F: Future>(
+    context: Option<TraceContext>,
+    fut: F,
+) -> TraceScoped<F> {
+    let Some((trace_id, span_id, sampled)) = context else {
+        return TASK_LOCAL_TRACE_ID.scope(None, fut).instrument(Span::none());
+    };
+    let span = info_span!(
+        "trace_id",
+        trace_id = %trace_id,
+        span_id = Empty,
+        trace_sampled = Empty
+    );
+    if let Some(span_id) = span_id {
+        span.record("span_id", span_id);
+    }
+    if let Some(sampled) = sampled {
+        span.record("trace_sampled", sampled);
+    }
+    TASK_LOCAL_TRACE_ID.scope(Some(trace_id), fut).instrument(span)
+}
+
+/// The trace id set up by [`with_trace_id`] for the current task, e.g. to propagate
+/// it in the headers of outbound requests. It is lowercased, and None when it isn't
+/// valid, as the entries then have no trace.
+pub fn current_trace_id() -> Option<String> {
+    let trace_id = TASK_LOCAL_TRACE_ID.try_with(|trace_id| trace_id.clone()).ok()??;
+    normalize_trace_id(&trace_id)
+}
+
+/// A future running with the correlation id set up by [`with_correlation_id`].
+pub type CorrelationScoped<F> = Instrumented<TaskLocalFuture<String, F>>;
+
+/// Runs `fut` with the `correlation_id` label for both backends: inside a
+/// `correlation_id` span, which `GcpLayer` reads, and with
+/// [`TASK_LOCAL_CORRELATION_ID`] set, which `GcpJsonEncoder` reads. Unlike the trace,
+/// it needs no Cloud Trace: it is only there to find the entries of a request.
+pub fn with_correlation_id<F: Future>(
+    correlation_id: impl Into<String>,
+    fut: F,
+) -> CorrelationScoped<F> {
+    let correlation_id = correlation_id.into();
+    let span = info_span!("correlation_id", correlation_id = %correlation_id);
+    TASK_LOCAL_CORRELATION_ID.scope(correlation_id, fut).instrument(span)
+}
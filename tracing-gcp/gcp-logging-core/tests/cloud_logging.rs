@@ -0,0 +1,129 @@
+//! The requests the Cloud Logging sink sends, to a mock `entries.write` endpoint.
+#![cfg(feature = "cloud-logging")]
+
+use gcp_logging_core::CloudLoggingBuilder;
+use serde_json::{Value, json};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// A request received by [`serve`]: its headers, lowercased, and its body.
+struct Request {
+    headers: String,
+    body: Value,
+}
+
+/// Answers the requests sent to the returned URL with `statuses`, in order, keeping
+/// the requests.
+async fn serve(statuses: &'static [u16]) -> (String, Arc<Mutex<Vec<Request>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/v2/entries:write", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let received = requests.clone();
+    tokio::spawn(async move {
+        for status in statuses {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            let body_start = loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break end + 4;
+                }
+            };
+            let headers = String::from_utf8_lossy(&request[..body_start]).to_lowercase();
+            let length: usize = headers
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length: "))
+                .unwrap()
+                .trim()
+                .parse()
+                .unwrap();
+            while request.len() < body_start + length {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            let body = serde_json::from_slice(&request[body_start..]).unwrap();
+            received.lock().unwrap().push(Request { headers, body });
+            let response = format!(
+                "HTTP/1.1 {status} Status\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+    (url, requests)
+}
+
+/// Writes entries as `with_serialized_line` would.
+fn write_entries(mut writer: impl Write) -> io::Result<()> {
+    writer.write_all(b"{\"severity\":\"INFO\",\"message\":\"first\",\"time\":\"2024-05-01T12:00:00Z\"}\n")?;
+    writer.write_all(b"{\"severity\":\"ERROR\",\"message\":\"second\",\"count\":3}\n")
+}
+
+#[tokio::test]
+async fn posts_batches_of_api_entries() {
+    let (url, requests) = serve(&[200]).await;
+    let (writer, guard) = CloudLoggingBuilder::new("projects/p/logs/app", || async {
+        Ok("the-token".to_owned())
+    })
+    .endpoint(url)
+    .batch(10, Duration::from_millis(10))
+    .spawn();
+    write_entries(writer).unwrap();
+    assert!(guard.shutdown().await);
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].headers.contains("authorization: bearer the-token"));
+    assert!(requests[0].headers.contains("content-type: application/json"));
+    assert_eq!(
+        requests[0].body,
+        json!({
+            "logName": "projects/p/logs/app",
+            "resource": { "type": "global", "labels": {} },
+            "entries": [
+                {
+                    "severity": "INFO",
+                    "timestamp": "2024-05-01T12:00:00Z",
+                    "jsonPayload": { "message": "first" },
+                },
+                {
+                    "severity": "ERROR",
+                    "jsonPayload": { "message": "second", "count": 3 },
+                },
+            ],
+        })
+    );
+}
+
+#[tokio::test]
+async fn retries_batches_on_429_and_5xx() {
+    let (url, requests) = serve(&[429, 503, 200]).await;
+    let (writer, guard) =
+        CloudLoggingBuilder::new("projects/p/logs/app", || async { Ok("t".to_owned()) })
+            .endpoint(url)
+            .batch(10, Duration::from_millis(10))
+            .spawn();
+    write_entries(writer).unwrap();
+    assert!(guard.shutdown().await);
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 3);
+    assert!(requests.iter().all(|r| r.body == requests[0].body));
+}
+
+#[tokio::test]
+async fn gives_up_on_other_errors() {
+    let (url, requests) = serve(&[400]).await;
+    let (writer, guard) =
+        CloudLoggingBuilder::new("projects/p/logs/app", || async { Ok("t".to_owned()) })
+            .endpoint(url)
+            .batch(10, Duration::from_millis(10))
+            .spawn();
+    write_entries(writer).unwrap();
+    assert!(!guard.shutdown().await);
+    assert_eq!(requests.lock().unwrap().len(), 1);
+}
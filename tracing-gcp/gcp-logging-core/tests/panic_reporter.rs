@@ -0,0 +1,38 @@
+//! The panic hook is global, so this test runs in its own binary.
+
+use gcp_logging_core::{REPORTED_ERROR_EVENT_TYPE, install_panic_reporter};
+use serde_json::Value;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+/// Appends to a shared buffer.
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn reports_panics_with_their_backtrace() {
+    let output = Shared::default();
+    let writer = output.clone();
+    install_panic_reporter("test-project", move || writer.clone());
+
+    let result = std::thread::spawn(|| panic!("the answer is {}", 42)).join();
+    assert!(result.is_err());
+
+    let output = output.0.lock().unwrap();
+    let entry: Value = serde_json::from_slice(&output).unwrap();
+    assert_eq!(entry["severity"], "CRITICAL");
+    assert_eq!(entry["@type"], REPORTED_ERROR_EVENT_TYPE);
+    let message = entry["message"].as_str().unwrap();
+    assert!(message.starts_with("panic: the answer is 42 at "), "{message}");
+    assert!(message.contains("\n\tat "), "{message}");
+}
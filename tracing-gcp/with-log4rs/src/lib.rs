@@ -0,0 +1,422 @@
+//! A log4rs encoder that writes Cloud Logging structured JSON entries.
+
+use gcp_logging_core::{
+    Clock, EntryKeys, FieldBudget, FieldFilter, LogEntry, LogError, MAX_ENTRY_SIZE,
+    MissingProjectId, MonitoredResource, OnError, OversizedEntry, PayloadFormat,
+    SkipFields, SourceLocation, SystemClock, TASK_LOCAL_CORRELATION_ID, TargetPlacement,
+    Timestamp, TimestampFormat, WriteOptions, add_thread_labels, current_trace_id,
+    extract_labels, float_to_json, format_log_name, format_trace, level_to_gcp_severity,
+    merge_json_payload, project_id_from_env, truncate_values, write_entry,
+};
+use log::kv::{self, Key, VisitSource};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Formats records as Cloud Logging structured JSON entries.
+pub struct GcpJsonEncoder {
+    gcp_project_id: String,
+    /// Adds the `logging.googleapis.com/sourceLocation` of the log call.
+    with_source_location: bool,
+    default_labels: HashMap<String, String>,
+    timestamp_format: TimestampFormat,
+    target_placement: TargetPlacement,
+    resource: Option<MonitoredResource>,
+    clock: Box<dyn Clock + Send + Sync>,
+    /// The key of the message, `message` unless the pipeline expects another one.
+    message_key: String,
+    /// The key of the time, when the pipeline expects another one than `time`.
+    time_key: Option<String>,
+    /// Adds the `thread.id`, `thread.name` and `process.pid` labels.
+    with_thread_labels: bool,
+    /// `projects/PROJECT_ID/logs/LOG_ID`, see [`format_log_name`].
+    log_name: Option<String>,
+    field_filter: FieldFilter,
+    /// Drops the fields for which it returns true given their name and value.
+    skip_fields: Option<SkipFields>,
+    /// Called when an entry can't be serialized or written, instead of failing the
+    /// append.
+    on_error: Option<OnError>,
+    /// Truncates the message and string fields longer than this many bytes.
+    max_value_len: Option<usize>,
+    /// Drops fields once they take more than the budget.
+    field_budget: Option<FieldBudget>,
+    write_options: WriteOptions,
+}
+
+impl fmt::Debug for GcpJsonEncoder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GcpJsonEncoder")
+            .field("gcp_project_id", &self.gcp_project_id)
+            .field("with_source_location", &self.with_source_location)
+            .field("default_labels", &self.default_labels)
+            .field("timestamp_format", &self.timestamp_format)
+            .field("target_placement", &self.target_placement)
+            .field("resource", &self.resource)
+            .field("message_key", &self.message_key)
+            .field("time_key", &self.time_key)
+            .field("with_thread_labels", &self.with_thread_labels)
+            .field("log_name", &self.log_name)
+            .field("field_filter", &self.field_filter)
+            .field("max_value_len", &self.max_value_len)
+            .field("field_budget", &self.field_budget)
+            .field("write_options", &self.write_options)
+            .finish_non_exhaustive()
+    }
+}
+
+impl GcpJsonEncoder {
+    pub fn builder() -> GcpJsonEncoderBuilder {
+        GcpJsonEncoderBuilder {
+            gcp_project_id: None,
+            with_source_location: false,
+            default_labels: HashMap::new(),
+            timestamp_format: TimestampFormat::default(),
+            target_placement: TargetPlacement::default(),
+            resource: None,
+            clock: Box::new(SystemClock),
+            pretty: false,
+            message_key: "message".into(),
+            time_key: None,
+            numeric_severity: false,
+            record_delimiter: b'\n',
+            with_thread_labels: false,
+            payload_format: PayloadFormat::default(),
+            log_name: None,
+            field_filter: FieldFilter::default(),
+            skip_fields: None,
+            on_error: None,
+            max_value_len: None,
+            field_budget: None,
+            entry_size_limit: (MAX_ENTRY_SIZE, OversizedEntry::Drop),
+            version: None,
+            entry_keys: EntryKeys::Gcp,
+        }
+    }
+}
+
+/// Configures a [`GcpJsonEncoder`], like `GcpLayerBuilder` does the `GcpLayer` of the
+/// `with-tracing` example.
+pub struct GcpJsonEncoderBuilder {
+    gcp_project_id: Option<String>,
+    with_source_location: bool,
+    default_labels: HashMap<String, String>,
+    timestamp_format: TimestampFormat,
+    target_placement: TargetPlacement,
+    resource: Option<MonitoredResource>,
+    clock: Box<dyn Clock + Send + Sync>,
+    pretty: bool,
+    message_key: String,
+    time_key: Option<String>,
+    numeric_severity: bool,
+    record_delimiter: u8,
+    with_thread_labels: bool,
+    payload_format: PayloadFormat,
+    log_name: Option<String>,
+    field_filter: FieldFilter,
+    skip_fields: Option<SkipFields>,
+    on_error: Option<OnError>,
+    max_value_len: Option<usize>,
+    field_budget: Option<FieldBudget>,
+    entry_size_limit: (usize, OversizedEntry),
+    version: Option<String>,
+    entry_keys: EntryKeys,
+}
+
+impl GcpJsonEncoderBuilder {
+    pub fn project_id(mut self, gcp_project_id: impl Into<String>) -> Self {
+        self.gcp_project_id = Some(gcp_project_id.into());
+        self
+    }
+
+    /// Adds `logging.googleapis.com/sourceLocation` to every entry.
+    pub fn with_source_location(mut self, with_source_location: bool) -> Self {
+        self.with_source_location = with_source_location;
+        self
+    }
+
+    /// Labels added to `logging.googleapis.com/labels` on every entry. Key-values
+    /// named `label.<key>` add to or override them.
+    pub fn default_labels(mut self, default_labels: HashMap<String, String>) -> Self {
+        self.default_labels = default_labels;
+        self
+    }
+
+    /// Writes `timestamp` as seconds and nanos instead of the default RFC3339 `time`.
+    pub fn timestamp_format(mut self, timestamp_format: TimestampFormat) -> Self {
+        self.timestamp_format = timestamp_format;
+        self
+    }
+
+    /// Records each record's target as a label or a payload member.
+    pub fn target_placement(mut self, target_placement: TargetPlacement) -> Self {
+        self.target_placement = target_placement;
+        self
+    }
+
+    /// Sets the `resource` entries are attached to, e.g.
+    /// [`MonitoredResource::detect_cloud_run`]. None, the default, omits it.
+    pub fn resource(mut self, resource: Option<MonitoredResource>) -> Self {
+        self.resource = resource;
+        self
+    }
+
+    pub fn clock(mut self, clock: impl Clock + Send + Sync + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Indents entries over several lines, for reading logs locally. Cloud Logging
+    /// needs the default single-line entries.
+    pub fn pretty(mut self, pretty: bool) -> Self {
+        self.pretty = pretty;
+        self
+    }
+
+    /// Puts the message under `message_key` instead of `message`, e.g. `textPayload`.
+    pub fn message_key(mut self, message_key: impl Into<String>) -> Self {
+        self.message_key = message_key.into();
+        self
+    }
+
+    /// Puts the time under `time_key` instead of `time` (or `timestamp`).
+    pub fn time_key(mut self, time_key: impl Into<String>) -> Self {
+        self.time_key = Some(time_key.into());
+        self
+    }
+
+    /// Writes `severity` as its `LogSeverity` number, e.g. 400 for WARNING, instead of
+    /// its name.
+    pub fn numeric_severity(mut self, numeric_severity: bool) -> Self {
+        self.numeric_severity = numeric_severity;
+        self
+    }
+
+    /// Writes the special members under `entry_keys`, e.g. [`EntryKeys::Generic`] for
+    /// plain `trace`, `span_id` and `labels` keys outside of GCP.
+    pub fn entry_keys(mut self, entry_keys: EntryKeys) -> Self {
+        self.entry_keys = entry_keys;
+        self
+    }
+
+    /// Ends entries with `record_delimiter` rather than a newline, e.g. `b'\0'` for
+    /// collectors that read NUL-delimited JSON.
+    pub fn record_delimiter(mut self, record_delimiter: u8) -> Self {
+        self.record_delimiter = record_delimiter;
+        self
+    }
+
+    /// Adds the `thread.id`, `thread.name` and `process.pid` labels to every entry.
+    pub fn with_thread_labels(mut self, with_thread_labels: bool) -> Self {
+        self.with_thread_labels = with_thread_labels;
+        self
+    }
+
+    /// Writes entries with nothing but a message as plain text, see [`PayloadFormat`].
+    pub fn payload_format(mut self, payload_format: PayloadFormat) -> Self {
+        self.payload_format = payload_format;
+        self
+    }
+
+    /// Routes entries to the log `log_id` of the project rather than the default one.
+    pub fn log_name(mut self, log_id: impl Into<String>) -> Self {
+        self.log_name = Some(log_id.into());
+        self
+    }
+
+    /// Redacts or drops the key-values named by `field_filter`.
+    pub fn field_filter(mut self, field_filter: FieldFilter) -> Self {
+        self.field_filter = field_filter;
+        self
+    }
+
+    /// Drops the key-values for which `skip_fields` returns true given their name and
+    /// value.
+    pub fn skip_fields(
+        mut self,
+        skip_fields: impl Fn(&str, &Value) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.skip_fields = Some(Box::new(skip_fields));
+        self
+    }
+
+    /// Calls `on_error` when an entry can't be serialized or written, instead of failing
+    /// the append. Without it, `encode` returns the error.
+    pub fn on_error(
+        mut self,
+        on_error: impl Fn(&LogError) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_error = Some(Box::new(on_error));
+        self
+    }
+
+    /// Truncates the message and string key-values longer than `max_value_len` bytes.
+    pub fn max_value_len(mut self, max_value_len: usize) -> Self {
+        self.max_value_len = Some(max_value_len);
+        self
+    }
+
+    /// Drops key-values once they take more than the budget, see [`FieldBudget`].
+    pub fn field_budget(mut self, field_budget: FieldBudget) -> Self {
+        self.field_budget = Some(field_budget);
+        self
+    }
+
+    /// Handles entries over `max_size` bytes as `oversized` says. By default, entries
+    /// over Cloud Logging's [`MAX_ENTRY_SIZE`] are replaced with a warning.
+    pub fn entry_size_limit(
+        mut self,
+        max_size: usize,
+        oversized: OversizedEntry,
+    ) -> Self {
+        self.entry_size_limit = (max_size, oversized);
+        self
+    }
+
+    /// Adds a `version` label, e.g. from [`gcp_logging_core::service_version_from_env`].
+    /// None, the default, omits it.
+    pub fn version(mut self, version: Option<String>) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Falls back to the `GOOGLE_CLOUD_PROJECT` env var when no project id was set.
+    pub fn build(mut self) -> Result<GcpJsonEncoder, MissingProjectId> {
+        let gcp_project_id =
+            self.gcp_project_id.or_else(project_id_from_env).ok_or(MissingProjectId)?;
+        if let Some(version) = self.version {
+            self.default_labels.entry("version".into()).or_insert(version);
+        }
+        let log_name = self.log_name.map(|id| format_log_name(&gcp_project_id, &id));
+        Ok(GcpJsonEncoder {
+            gcp_project_id,
+            with_source_location: self.with_source_location,
+            default_labels: self.default_labels,
+            timestamp_format: self.timestamp_format,
+            target_placement: self.target_placement,
+            resource: self.resource,
+            clock: self.clock,
+            message_key: self.message_key,
+            time_key: self.time_key,
+            with_thread_labels: self.with_thread_labels,
+            log_name,
+            field_filter: self.field_filter,
+            skip_fields: self.skip_fields,
+            on_error: self.on_error,
+            max_value_len: self.max_value_len,
+            field_budget: self.field_budget,
+            write_options: WriteOptions {
+                pretty: self.pretty,
+                numeric_severity: self.numeric_severity,
+                record_delimiter: self.record_delimiter,
+                payload_format: self.payload_format,
+                entry_size_limit: self.entry_size_limit,
+                entry_keys: self.entry_keys,
+            },
+        })
+    }
+}
+
+#[derive(Default)]
+struct KeyValueVisitor {
+    fields: Map<String, Value>,
+}
+
+impl<'kvs> VisitSource<'kvs> for KeyValueVisitor {
+    fn visit_pair(
+        &mut self,
+        key: Key<'kvs>,
+        value: kv::Value<'kvs>,
+    ) -> Result<(), kv::Error> {
+        let value = if let Some(b) = value.to_bool() {
+            b.into()
+        } else if let Some(i) = value.to_i64() {
+            i.into()
+        } else if let Some(u) = value.to_u64() {
+            u.into()
+        } else if let Some(f) = value.to_f64() {
+            float_to_json(f)
+        } else if let Some(s) = value.to_borrowed_str() {
+            s.into()
+        } else {
+            value.to_string().into()
+        };
+        self.fields.insert(key.as_str().into(), value);
+        Ok(())
+    }
+}
+
+impl log4rs::encode::Encode for GcpJsonEncoder {
+    fn encode(
+        &self,
+        w: &mut dyn log4rs::encode::Write,
+        record: &log::Record,
+    ) -> anyhow::Result<()> {
+        // Read the clock first: the entry carries the time of the record, not that of
+        // the write.
+        let now = self.clock.now();
+        let trace_id = current_trace_id();
+        let trace = trace_id.and_then(|t| format_trace(&self.gcp_project_id, &t));
+        let mut visitor = KeyValueVisitor::default();
+        record.key_values().visit(&mut visitor)?;
+        let mut fields = visitor.fields;
+        self.field_filter.apply(&mut fields);
+        if let Some(skip_fields) = &self.skip_fields {
+            fields.retain(|name, value| !skip_fields(name, value));
+        }
+        let mut labels = self.default_labels.clone();
+        if self.with_thread_labels {
+            add_thread_labels(&mut labels);
+        }
+        if let Ok(correlation_id) = TASK_LOCAL_CORRELATION_ID.try_with(|c| c.clone()) {
+            labels.insert("correlation_id".into(), correlation_id);
+        }
+        extract_labels(&mut fields, &mut labels);
+        let mut message = if merge_json_payload(&mut fields) {
+            String::new()
+        } else {
+            record.args().to_string()
+        };
+        if let Some(max_len) = self.max_value_len {
+            truncate_values(&mut message, &mut fields, max_len);
+        }
+        self.target_placement.place(record.target(), &mut labels, &mut fields);
+        if let Some(field_budget) = &self.field_budget {
+            field_budget.apply(&mut fields);
+        }
+        let source_location = self.with_source_location.then(|| SourceLocation {
+            file: record.file(),
+            line: record.line(),
+            function: record.module_path().or(Some(record.target())),
+        });
+        let mut entry = LogEntry {
+            severity: level_to_gcp_severity(&record.level()),
+            message,
+            time: Timestamp::new(now, self.timestamp_format),
+            trace,
+            span_id: None,
+            trace_sampled: None,
+            source_location,
+            http_request: None,
+            insert_id: None,
+            log_name: self.log_name.as_deref(),
+            error_type: None,
+            service_context: None,
+            operation: None,
+            resource: self.resource.as_ref(),
+            labels,
+            fields,
+            message_key: None,
+            time_key: None,
+        };
+        entry.rename_message(&self.message_key);
+        if let Some(time_key) = &self.time_key {
+            entry.rename_time(time_key);
+        }
+        match (write_entry(w, &mut entry, self.write_options), &self.on_error) {
+            (Err(error), Some(on_error)) => on_error(&error),
+            (result, _) => result?,
+        }
+        Ok(())
+    }
+}
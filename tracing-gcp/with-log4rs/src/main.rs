@@ -1,68 +1,100 @@
-use chrono::{SecondsFormat, Utc};
+use gcp_logging_core::{
+    MonitoredResource, detect_project_id, get_gcp_project_id, service_version_from_env,
+    with_trace_id,
+};
 use log::{LevelFilter, info};
+use log4rs::append::Append;
 use log4rs::append::console::{ConsoleAppender, Target};
+use log4rs::append::rolling_file::RollingFileAppender;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
 use log4rs::config::{Appender, Config, Root};
-use serde::Serialize;
-use tokio::task_local;
+use std::env;
+use with_log4rs::GcpJsonEncoder;
 
-task_local! {
-    static TASK_LOCAL_TRACE_ID: Option<String>;
+async fn gcp_json_encoder() -> GcpJsonEncoder {
+    let gcp_project_id = detect_project_id().await.unwrap_or_else(get_gcp_project_id);
+    let mut builder = GcpJsonEncoder::builder()
+        .project_id(gcp_project_id)
+        .with_source_location(true)
+        .resource(MonitoredResource::detect_cloud_run())
+        .version(service_version_from_env().or(Some(env!("CARGO_PKG_VERSION").into())))
+        .pretty(env::var_os("LOG_PRETTY").is_some());
+    if let Ok(log_id) = env::var("LOG_NAME") {
+        builder = builder.log_name(log_id);
+    }
+    builder.build().expect("the project id is set")
 }
 
-#[derive(Debug)]
-struct GcpJsonEncoder {
-    gcp_project_id: String,
+/// Logs to stderr, or to the file named by `LOG_FILE` when it is set. Setting
+/// `LOG_PRETTY` indents entries for local reading, and `LOG_NAME` routes them to that
+/// log.
+///
+/// Panics if a logger is already installed or the log file can't be opened.
+async fn init_logging() {
+    try_init_logging().await.unwrap();
 }
 
-#[derive(Serialize)]
-struct LogEntry<'a> {
-    severity: &'a str,
-    message: String,
-    time: String,
-    #[serde(rename = "logging.googleapis.com/trace")]
-    #[serde(skip_serializing_if = "Option::is_none")]
-    trace: Option<String>,
+/// Like [`init_logging`], but fails instead of panicking.
+async fn try_init_logging() -> anyhow::Result<()> {
+    if let Ok(path) = env::var("LOG_FILE") {
+        return init_logging_to_file(&path, 10 * 1024 * 1024).await;
+    }
+    let stderr = ConsoleAppender::builder()
+        .target(Target::Stderr)
+        .encoder(Box::new(gcp_json_encoder().await))
+        .build();
+    init_with_appender(Box::new(stderr))
 }
 
-impl log4rs::encode::Encode for GcpJsonEncoder {
-    fn encode(
-        &self,
-        w: &mut dyn log4rs::encode::Write,
-        record: &log::Record,
-    ) -> anyhow::Result<()> {
-        let trace_id = TASK_LOCAL_TRACE_ID.try_with(|c| c.clone()).ok().flatten();
-        let trace =
-            trace_id.map(|t| format!("projects/{}/traces/{t}", self.gcp_project_id));
-        let entry = LogEntry {
-            severity: record.level().as_str(),
-            message: format!("{}", record.args()),
-            time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
-            trace,
-        };
-        w.write_all(&serde_json::to_vec(&entry).unwrap())?;
-        w.write_all("\n".as_bytes())?;
-        Ok(())
-    }
+/// Logs to `path`, rolling it over to `path.0` ... `path.4` once it exceeds
+/// `max_size` bytes.
+async fn init_logging_to_file(path: &str, max_size: u64) -> anyhow::Result<()> {
+    let file = rolling_file_appender(path, max_size, gcp_json_encoder().await)?;
+    init_with_appender(Box::new(file))
 }
 
-fn get_gcp_project_id() -> String {
-    "PROJECT_ID_123".into()
+/// The appender of [`init_logging_to_file`].
+fn rolling_file_appender(
+    path: &str,
+    max_size: u64,
+    encoder: GcpJsonEncoder,
+) -> anyhow::Result<RollingFileAppender> {
+    let roller = FixedWindowRoller::builder().build(&format!("{path}.{{}}"), 5)?;
+    let policy =
+        CompoundPolicy::new(Box::new(SizeTrigger::new(max_size)), Box::new(roller));
+    let file = RollingFileAppender::builder()
+        .encoder(Box::new(encoder))
+        .build(path, Box::new(policy))?;
+    Ok(file)
 }
 
-async fn init_logging() {
-    let stderr = ConsoleAppender::builder()
-        .target(Target::Stderr)
-        .encoder(Box::new(GcpJsonEncoder { gcp_project_id: get_gcp_project_id() }))
-        .build();
+/// Flushes the appender. Appenders write each entry as it is logged, so nothing is
+/// left to drain afterwards.
+fn shutdown() {
+    log::logger().flush();
+}
+
+fn init_with_appender(appender: Box<dyn Append>) -> anyhow::Result<()> {
     let config = Config::builder()
-        .appender(Appender::builder().build("stderr", Box::new(stderr)))
-        .build(Root::builder().appender("stderr").build(LevelFilter::Info))
-        .unwrap();
-    log4rs::init_config(config).unwrap();
+        .appender(Appender::builder().build("gcp", appender))
+        .build(Root::builder().appender("gcp").build(level_filter_from_env()))?;
+    log4rs::init_config(config)?;
+    Ok(())
+}
+
+/// Reads a level such as `debug` from `RUST_LOG`, or else `LOG_LEVEL`, defaulting to
+/// INFO.
+fn level_filter_from_env() -> LevelFilter {
+    ["RUST_LOG", "LOG_LEVEL"]
+        .into_iter()
+        .find_map(|var| env::var(var).ok()?.parse().ok())
+        .unwrap_or(LevelFilter::Info)
 }
 
 async fn do_something() {
-    info!("Doing something");
+    info!(count = 3; "Doing something");
     // ...
     info!("Done doing something");
 }
@@ -71,12 +103,104 @@ async fn do_something() {
 async fn main() {
     init_logging().await;
 
-    println!("With trace_id=456");
-    TASK_LOCAL_TRACE_ID.scope(Some("456".into()), do_something()).await;
+    println!("With trace_id=0...0456");
+    with_trace_id("00000000000000000000000000000456", do_something()).await;
 
     println!("Without a trace_id:");
     do_something().await;
 
-    println!("With trace_id=789");
-    TASK_LOCAL_TRACE_ID.scope(Some("789".into()), do_something()).await;
+    println!("With trace_id=0...0789");
+    with_trace_id("00000000000000000000000000000789", do_something()).await;
+
+    shutdown();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+    use serde_json::Value;
+    use std::sync::Mutex;
+
+    /// Serializes the tests that set environment variables.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn encoder() -> GcpJsonEncoder {
+        GcpJsonEncoder::builder().project_id("test-project").build().unwrap()
+    }
+
+    #[test]
+    fn reads_the_level_filter_from_the_environment() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let level_filter = |vars: &[(&str, &str)]| {
+            // SAFETY: the tests that read or write the environment hold `ENV`.
+            unsafe {
+                env::remove_var("RUST_LOG");
+                env::remove_var("LOG_LEVEL");
+                for (var, value) in vars {
+                    env::set_var(var, value);
+                }
+            }
+            level_filter_from_env()
+        };
+        assert_eq!(level_filter(&[]), LevelFilter::Info);
+        assert_eq!(level_filter(&[("LOG_LEVEL", "debug")]), LevelFilter::Debug);
+        assert_eq!(level_filter(&[("RUST_LOG", "warn")]), LevelFilter::Warn);
+        assert_eq!(level_filter(&[("LOG_LEVEL", "loud")]), LevelFilter::Info);
+    }
+
+    #[test]
+    fn writes_newline_delimited_json_to_the_file() {
+        let dir = env::temp_dir().join(format!("with-log4rs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+        let appender =
+            rolling_file_appender(path.to_str().unwrap(), 1024 * 1024, encoder())
+                .unwrap();
+        for i in 0..3 {
+            appender
+                .append(
+                    &log::Record::builder()
+                        .level(Level::Info)
+                        .args(format_args!("entry {i}"))
+                        .build(),
+                )
+                .unwrap();
+        }
+        appender.flush();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(contents.ends_with('\n'));
+        let messages: Vec<_> = contents
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap()["message"].clone())
+            .collect();
+        assert_eq!(messages, ["entry 0", "entry 1", "entry 2"]);
+    }
+
+    /// The logger is global, so this is the only test that installs one.
+    #[test]
+    fn writes_every_entry_by_shutdown_and_initializes_once() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let dir =
+            env::temp_dir().join(format!("with-log4rs-init-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+        let path = path.to_str().unwrap();
+        let appender =
+            || Box::new(rolling_file_appender(path, 1024 * 1024, encoder()).unwrap());
+        init_with_appender(appender()).unwrap();
+        for i in 0..3 {
+            info!("entry {i}");
+        }
+        shutdown();
+        let contents = std::fs::read_to_string(path).unwrap();
+        assert!(init_with_appender(appender()).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+        let messages: Vec<_> = contents
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap()["message"].clone())
+            .collect();
+        assert_eq!(messages, ["entry 0", "entry 1", "entry 2"]);
+    }
 }
@@ -0,0 +1,282 @@
+//! The entries `GcpJsonEncoder` writes for log records.
+
+use gcp_logging_core::{
+    EntryKeys, FieldFilter, FixedClock, TargetPlacement, with_trace_id,
+};
+use log::Level;
+use log::kv::{self, VisitSource};
+use log4rs::encode::Encode;
+use log4rs::encode::writer::simple::SimpleWriter;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use with_log4rs::{GcpJsonEncoder, GcpJsonEncoderBuilder};
+
+/// An encoder of the `test-project` project configured by `configure`.
+fn configured(
+    configure: impl FnOnce(GcpJsonEncoderBuilder) -> GcpJsonEncoderBuilder,
+) -> GcpJsonEncoder {
+    configure(GcpJsonEncoder::builder().project_id("test-project")).build().unwrap()
+}
+
+fn encoder() -> GcpJsonEncoder {
+    configured(|b| b)
+}
+
+/// The key-values of a record whose values can't be read.
+struct FailingSource;
+
+impl kv::Source for FailingSource {
+    fn visit<'kvs>(
+        &'kvs self,
+        _visitor: &mut dyn VisitSource<'kvs>,
+    ) -> Result<(), kv::Error> {
+        Err(kv::Error::msg("the value can't be serialized"))
+    }
+}
+
+#[test]
+fn returns_an_error_for_values_that_fail_to_serialize() {
+    let record = log::Record::builder()
+        .level(Level::Info)
+        .args(format_args!("unserializable"))
+        .key_values(&FailingSource)
+        .build();
+    let mut w = SimpleWriter(Vec::new());
+    let error = encoder().encode(&mut w, &record).unwrap_err();
+    assert!(error.to_string().contains("can't be serialized"), "{error}");
+    assert!(w.0.is_empty());
+}
+
+/// The entry `encoder` writes for `record`.
+fn encode(encoder: &GcpJsonEncoder, record: &log::Record) -> Value {
+    let mut w = SimpleWriter(Vec::new());
+    encoder.encode(&mut w, record).unwrap();
+    serde_json::from_slice(&w.0).unwrap()
+}
+
+#[test]
+fn merges_per_record_labels_over_the_default_ones() {
+    let default_labels = HashMap::from([
+        ("service".to_owned(), "api".to_owned()),
+        ("region".to_owned(), "europe-west1".to_owned()),
+    ]);
+    let encoder = configured(|b| b.default_labels(default_labels));
+    let record = log::Record::builder()
+        .level(Level::Info)
+        .args(format_args!("labelled"))
+        .key_values(&[("label.region", "us-east1")])
+        .build();
+    let entry = encode(&encoder, &record);
+    let labels = &entry["logging.googleapis.com/labels"];
+    assert_eq!(labels["service"], "api");
+    assert_eq!(labels["region"], "us-east1");
+    assert!(entry.get("label.region").is_none());
+}
+
+#[test]
+fn stamps_entries_with_the_injected_clock() {
+    let time = "2001-02-03T04:05:06.789Z".parse().unwrap();
+    let encoder = configured(|b| b.clock(FixedClock(time)));
+    let record = log::Record::builder().args(format_args!("frozen")).build();
+    assert_eq!(encode(&encoder, &record)["time"], "2001-02-03T04:05:06.789Z");
+}
+
+#[test]
+fn writes_key_values_as_payload_members() {
+    let record = log::Record::builder()
+        .level(Level::Info)
+        .target("x")
+        .args(format_args!("done"))
+        .key_values(&[("status", 200)])
+        .build();
+    let entry = encode(&encoder(), &record);
+    assert_eq!(entry["status"], 200);
+    assert_eq!(entry["message"], "done");
+}
+
+#[tokio::test]
+async fn writes_the_trace_of_with_trace_id() {
+    let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+    let entry = with_trace_id(trace_id, async {
+        let record = log::Record::builder().args(format_args!("traced")).build();
+        encode(&encoder(), &record)
+    })
+    .await;
+    assert_eq!(
+        entry["logging.googleapis.com/trace"],
+        format!("projects/test-project/traces/{trace_id}")
+    );
+}
+
+#[test]
+fn writes_the_target_as_a_label() {
+    let encoder = configured(|b| b.target_placement(TargetPlacement::Label));
+    let record =
+        log::Record::builder().target("billing").args(format_args!("charged")).build();
+    let entry = encode(&encoder, &record);
+    assert_eq!(entry["logging.googleapis.com/labels"]["target"], "billing");
+}
+
+#[test]
+fn writes_the_thread_labels() {
+    let encoder = configured(|b| b.with_thread_labels(true));
+    let entry = std::thread::Builder::new()
+        .name("worker".into())
+        .spawn(move || {
+            encode(&encoder, &log::Record::builder().args(format_args!("")).build())
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+    let labels = &entry["logging.googleapis.com/labels"];
+    assert_eq!(labels["thread.name"], "worker");
+    assert!(labels["thread.id"].as_str().unwrap().parse::<u64>().is_ok());
+    assert_eq!(labels["process.pid"], std::process::id().to_string());
+}
+
+#[test]
+fn writes_the_module_path_as_the_function() {
+    let encoder = configured(|b| b.with_source_location(true));
+    let record = log::Record::builder()
+        .module_path(Some("app::orders"))
+        .target("orders")
+        .args(format_args!("here"))
+        .build();
+    let entry = encode(&encoder, &record);
+    assert_eq!(entry["logging.googleapis.com/sourceLocation"]["function"], "app::orders");
+
+    let record =
+        log::Record::builder().target("orders").args(format_args!("here")).build();
+    let entry = encode(&encoder, &record);
+    assert_eq!(entry["logging.googleapis.com/sourceLocation"]["function"], "orders");
+}
+
+#[test]
+fn redacts_and_drops_the_filtered_key_values() {
+    let field_filter =
+        FieldFilter::default().redact_fields(["password"]).drop_fields(["token"]);
+    let encoder = configured(|b| b.field_filter(field_filter));
+    let record = log::Record::builder()
+        .args(format_args!("signed in"))
+        .key_values(&[("PassWord", "hunter2"), ("Token", "abc"), ("user", "alice")])
+        .build();
+    let entry = encode(&encoder, &record);
+    assert_eq!(entry["PassWord"], "***");
+    assert!(entry.get("Token").is_none());
+    assert_eq!(entry["user"], "alice");
+}
+
+/// A writer whose writes all fail.
+struct FailingWriter;
+
+impl io::Write for FailingWriter {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::other("disk full"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn returns_write_errors_without_an_error_callback() {
+    let record =
+        log::Record::builder().level(Level::Info).args(format_args!("lost")).build();
+    let error = encoder().encode(&mut SimpleWriter(FailingWriter), &record).unwrap_err();
+    assert!(error.to_string().contains("disk full"), "{error}");
+}
+
+#[test]
+fn hands_write_errors_to_the_error_callback() {
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let encoder = configured(|b| {
+        let errors = errors.clone();
+        b.on_error(move |error| errors.lock().unwrap().push(error.to_string()))
+    });
+    let record =
+        log::Record::builder().level(Level::Info).args(format_args!("lost")).build();
+    encoder.encode(&mut SimpleWriter(FailingWriter), &record).unwrap();
+    let errors = errors.lock().unwrap();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].contains("disk full"), "{errors:?}");
+}
+
+/// Encodes the records logged through it, without installing a global logger.
+struct EncodingLogger {
+    encoder: GcpJsonEncoder,
+    entries: Mutex<Vec<Value>>,
+}
+
+impl log::Log for EncodingLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.entries.lock().unwrap().push(encode(&self.encoder, record));
+    }
+
+    fn flush(&self) {}
+}
+
+#[test]
+fn writes_the_file_and_line_of_the_log_call() {
+    let logger = EncodingLogger {
+        encoder: configured(|b| b.with_source_location(true)),
+        entries: Mutex::default(),
+    };
+    // The macros check the global max level, which is off without a global logger.
+    log::set_max_level(log::LevelFilter::Info);
+    let line = line!() + 1;
+    log::info!(logger: logger, "here");
+    let entries = logger.entries.into_inner().unwrap();
+    let location = &entries[0]["logging.googleapis.com/sourceLocation"];
+    assert_eq!(location["file"], file!());
+    assert_eq!(location["line"], line);
+    assert_eq!(location["function"], module_path!());
+
+    let entry =
+        encode(&encoder(), &log::Record::builder().args(format_args!("x")).build());
+    assert!(entry.get("logging.googleapis.com/sourceLocation").is_none());
+}
+
+#[test]
+fn writes_the_time_under_the_configured_key() {
+    let now = "2024-05-01T12:00:00.123Z".parse().unwrap();
+    let encoder = configured(|b| b.clock(FixedClock(now)).time_key("timestamp"));
+    let entry = encode(&encoder, &log::Record::builder().args(format_args!("x")).build());
+    assert_eq!(entry["timestamp"], "2024-05-01T12:00:00.123Z");
+    assert!(entry.get("time").is_none());
+}
+
+#[test]
+fn writes_numeric_severities() {
+    let encoder = configured(|b| b.numeric_severity(true));
+    let entry = encode(
+        &encoder,
+        &log::Record::builder().level(Level::Warn).args(format_args!("warn")).build(),
+    );
+    assert_eq!(entry["severity"], 400);
+}
+
+#[test]
+fn writes_plain_keys_in_the_generic_mode() {
+    let encoder =
+        configured(|b| b.entry_keys(EntryKeys::Generic).with_source_location(true));
+    let record = log::Record::builder()
+        .file(Some("src/main.rs"))
+        .args(format_args!("generic"))
+        .key_values(&[("label.env", "prod")])
+        .build();
+    let entry = encode(&encoder, &record);
+    let entry = entry.as_object().unwrap();
+    assert!(
+        entry.keys().all(|key| !key.starts_with("logging.googleapis.com/")),
+        "{entry:?}"
+    );
+    assert_eq!(entry["labels"], serde_json::json!({ "env": "prod" }));
+    assert_eq!(entry["source_location"]["file"], "src/main.rs");
+}
@@ -0,0 +1,1381 @@
+//! A `tracing` layer that writes Cloud Logging structured JSON entries.
+
+use gcp_logging_core::{
+    Clock, EntryKeys, FieldBudget, FieldFilter, HttpRequest, InsertIdGenerator, LogEntry,
+    LogError, MAX_ENTRY_SIZE, MonitoredResource, OnError, Operation, OversizedEntry,
+    PayloadFormat, REPORTED_ERROR_EVENT_TYPE, ServiceContext, SkipFields, SourceLocation,
+    SystemClock, TargetPlacement, Timestamp, TimestampFormat, WriteOptions,
+    add_thread_labels, detect_project_id, eprint_log_error, extract_labels,
+    float_to_json, format_log_name, format_trace, format_trace_with_prefix,
+    level_to_gcp_severity, max_severity, merge_json_payload, new_correlation_id,
+    new_operation_id, normalize_trace_id, parse_gcp_severity, project_id_from_env,
+    running_on_gcp, trace_prefix, truncate_values, write_entry,
+};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::env;
+use std::hash::{BuildHasher, Hasher, RandomState};
+#[cfg(feature = "protobuf")]
+use std::io::Write;
+use std::io::{self, Stderr};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Span, Subscriber};
+use tracing_subscriber::Layer;
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::fmt::writer::{MakeWriterExt, OrElse, WithMaxLevel};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::{LookupSpan, Registry};
+use tracing_subscriber::reload;
+
+pub use gcp_logging_core::{MissingProjectId, with_trace_id};
+
+struct TraceId {
+    id: String,
+    sampled: bool,
+    /// Set by a `trace_project` field, for traces kept in another project.
+    project: Option<String>,
+}
+
+impl TraceId {
+    fn format(&self, default_prefix: &str) -> Option<String> {
+        match &self.project {
+            Some(project) => format_trace(project, &self.id),
+            None => format_trace_with_prefix(default_prefix, &self.id),
+        }
+    }
+}
+
+struct SpanId(String);
+
+/// The `correlation_id` label of the entries in a span.
+struct CorrelationId(String);
+
+/// Set by a `min_severity` field: the events in the span are logged at this severity
+/// or above.
+struct MinSeverity(&'static str);
+
+/// Reads the trace id, span id and sampled flag of an OpenTelemetry span.
+#[cfg(feature = "opentelemetry")]
+fn otel_ids(data: &tracing_opentelemetry::OtelData) -> Option<(String, String, bool)> {
+    use opentelemetry::trace::{SamplingDecision, TraceContextExt};
+    let parent = data.parent_cx.span();
+    let parent = parent.span_context();
+    let trace_id = match data.builder.trace_id {
+        Some(trace_id) => trace_id,
+        None if parent.is_valid() => parent.trace_id(),
+        None => return None,
+    };
+    let span_id = data.builder.span_id?;
+    let sampled = match &data.builder.sampling_result {
+        Some(result) => result.decision == SamplingDecision::RecordAndSample,
+        None => !parent.is_valid() || parent.is_sampled(),
+    };
+    Some((trace_id.to_string(), span_id.to_string(), sampled))
+}
+
+/// Adds the baggage of `cx` to `labels`.
+#[cfg(feature = "opentelemetry")]
+fn add_baggage_labels(cx: &opentelemetry::Context, labels: &mut HashMap<String, String>) {
+    use opentelemetry::baggage::BaggageExt;
+    for (key, (value, _)) in cx.baggage() {
+        labels.insert(key.to_string(), value.to_string());
+    }
+}
+
+struct SpanFields(Map<String, Value>);
+
+struct SpanStart(Instant);
+
+/// Marks a span whose events were not sampled, see [`GcpLayerBuilder::sample_spans`].
+struct SampledOut;
+
+struct SpanSampling {
+    probability: f64,
+    span_names: Vec<&'static str>,
+}
+
+impl SpanSampling {
+    fn keeps(&self) -> bool {
+        let random = RandomState::new().build_hasher().finish() >> 11;
+        (random as f64) < self.probability * (1u64 << 53) as f64
+    }
+}
+
+/// The operation of a span with an `operation_id` field, or an `operation = true` one
+/// for a generated id.
+struct SpanOperation {
+    id: String,
+    producer: Option<String>,
+    first_emitted: AtomicBool,
+}
+
+impl SpanOperation {
+    fn for_event(&self) -> Operation {
+        Operation {
+            id: self.id.clone(),
+            producer: self.producer.clone(),
+            first: !self.first_emitted.swap(true, Ordering::Relaxed),
+            last: false,
+        }
+    }
+}
+
+/// The span fields `GcpLayer` turns into entry metadata rather than payload fields.
+const SPAN_METADATA_FIELDS: &[&str] = &[
+    "trace_id",
+    "trace_sampled",
+    "trace_project",
+    "span_id",
+    "correlation_id",
+    "operation",
+    "operation_id",
+    "operation_producer",
+    "sample",
+    "min_severity",
+];
+
+/// The event fields `GcpLayer` turns into entry metadata rather than payload fields.
+const EVENT_METADATA_FIELDS: &[&str] = &["message", "severity"];
+
+/// Records the fields of a span or event in one pass, setting aside those named in
+/// `wanted` from the rest.
+struct FieldExtractor {
+    wanted: &'static [&'static str],
+    extracted: HashMap<&'static str, Value>,
+    fields: Map<String, Value>,
+}
+
+impl FieldExtractor {
+    fn new(wanted: &'static [&'static str]) -> Self {
+        FieldExtractor { wanted, extracted: HashMap::new(), fields: Map::new() }
+    }
+
+    fn record_value(&mut self, field: &Field, value: Value) {
+        if self.wanted.contains(&field.name()) {
+            self.extracted.insert(field.name(), value);
+        } else {
+            self.fields.insert(field.name().into(), value);
+        }
+    }
+
+    /// Takes an extracted field, formatting it if it isn't a string.
+    fn take_string(&mut self, name: &str) -> Option<String> {
+        match self.extracted.remove(name)? {
+            Value::String(s) => Some(s),
+            other => Some(other.to_string()),
+        }
+    }
+
+    /// Takes a field naming a `LogSeverity`, ignoring it if it names none.
+    fn take_severity(&mut self, name: &str) -> Option<&'static str> {
+        parse_gcp_severity(&self.take_string(name)?)
+    }
+
+    /// Records an integer `trace_id` as its 32 hex digits, dropping negative ids.
+    /// Returns false for other fields.
+    fn record_trace_id(&mut self, field: &Field, id: impl TryInto<u128>) -> bool {
+        if field.name() != "trace_id" || !self.wanted.contains(&"trace_id") {
+            return false;
+        }
+        if let Ok(id) = id.try_into() {
+            self.record_value(field, format!("{id:032x}").into());
+        }
+        true
+    }
+
+    /// Takes the `trace_id` field, trimmed of whitespace and of the quotes `?`
+    /// formatting adds. Empty ids are dropped.
+    fn take_trace_id(&mut self) -> Option<String> {
+        let Value::String(id) = self.extracted.remove("trace_id")? else { return None };
+        let id = id.trim().trim_matches('"');
+        (!id.is_empty()).then(|| id.to_owned())
+    }
+}
+
+impl Visit for FieldExtractor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.record_value(field, float_to_json(value))
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if !self.record_trace_id(field, value) {
+            self.record_value(field, value.into())
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if !self.record_trace_id(field, value) {
+            self.record_value(field, value.into())
+        }
+    }
+
+    /// Records a number when it fits in 64 bits, as JSON numbers are read as doubles
+    /// anyway, and a decimal string otherwise.
+    fn record_i128(&mut self, field: &Field, value: i128) {
+        if self.record_trace_id(field, value) {
+            return;
+        }
+        let value =
+            i64::try_from(value).map_or_else(|_| value.to_string().into(), Value::from);
+        self.record_value(field, value)
+    }
+
+    /// See `record_i128`.
+    fn record_u128(&mut self, field: &Field, value: u128) {
+        if self.record_trace_id(field, value) {
+            return;
+        }
+        let value =
+            u64::try_from(value).map_or_else(|_| value.to_string().into(), Value::from);
+        self.record_value(field, value)
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record_value(field, value.into())
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_value(field, value.into())
+    }
+
+    /// Records the messages of the error and of its sources, outermost first.
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        let chain = std::iter::successors(Some(value), |e| e.source());
+        self.record_value(field, chain.map(|e| e.to_string()).collect())
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.record_value(field, format!("{value:?}").into())
+    }
+}
+
+/// Formats events as Cloud Logging structured JSON and writes them to `W`.
+///
+/// An event outside of any span, without fields, is written as exactly `severity`,
+/// `message` and `time` unless the builder adds labels, a resource or other members.
+pub struct GcpLayer<W = fn() -> Stderr> {
+    /// `projects/PROJECT_ID/traces/`, see [`trace_prefix`].
+    trace_prefix: String,
+    with_source_location: bool,
+    writer: W,
+    default_labels: HashMap<String, String>,
+    timestamp_format: TimestampFormat,
+    clock: Box<dyn Clock + Send + Sync>,
+    insert_ids: Option<InsertIdGenerator>,
+    error_reporting: Option<ServiceContext>,
+    target_placement: TargetPlacement,
+    resource: Option<MonitoredResource>,
+    with_span_timing: bool,
+    #[cfg(feature = "opentelemetry")]
+    with_opentelemetry: bool,
+    #[cfg(feature = "opentelemetry")]
+    with_baggage_labels: bool,
+    #[cfg(feature = "protobuf")]
+    protobuf: bool,
+    message_key: String,
+    with_thread_labels: bool,
+    span_sampling: Option<SpanSampling>,
+    log_name: Option<String>,
+    report_threshold: Level,
+    field_filter: FieldFilter,
+    dynamic_labels: Option<Box<dyn Fn() -> HashMap<String, String> + Send + Sync>>,
+    on_error: OnError,
+    span_name_labels: SpanNameLabels,
+    max_value_len: Option<usize>,
+    with_correlation_ids: bool,
+    time_key: Option<String>,
+    field_budget: Option<FieldBudget>,
+    skip_fields: Option<SkipFields>,
+    write_options: WriteOptions,
+}
+
+impl GcpLayer {
+    pub fn builder() -> GcpLayerBuilder {
+        GcpLayerBuilder {
+            gcp_project_id: None,
+            with_source_location: false,
+            writer: std::io::stderr,
+            default_labels: HashMap::new(),
+            timestamp_format: TimestampFormat::default(),
+            clock: Box::new(SystemClock),
+            insert_ids: None,
+            error_reporting: None,
+            target_placement: TargetPlacement::default(),
+            resource: None,
+            with_span_timing: false,
+            pretty: false,
+            #[cfg(feature = "opentelemetry")]
+            with_opentelemetry: false,
+            #[cfg(feature = "opentelemetry")]
+            with_baggage_labels: false,
+            #[cfg(feature = "protobuf")]
+            protobuf: false,
+            message_key: "message".into(),
+            with_thread_labels: false,
+            payload_format: PayloadFormat::default(),
+            span_sampling: None,
+            log_name: None,
+            report_threshold: Level::ERROR,
+            field_filter: FieldFilter::default(),
+            dynamic_labels: None,
+            on_error: Box::new(eprint_log_error),
+            span_name_labels: SpanNameLabels::default(),
+            max_value_len: None,
+            with_correlation_ids: false,
+            entry_size_limit: (MAX_ENTRY_SIZE, OversizedEntry::Drop),
+            time_key: None,
+            numeric_severity: false,
+            field_budget: None,
+            version: None,
+            record_delimiter: b'\n',
+            skip_fields: None,
+            entry_keys: EntryKeys::Gcp,
+        }
+    }
+}
+
+/// Configures a [`GcpLayer`]. Entries go to stderr unless another writer is set.
+pub struct GcpLayerBuilder<W = fn() -> Stderr> {
+    gcp_project_id: Option<String>,
+    with_source_location: bool,
+    writer: W,
+    default_labels: HashMap<String, String>,
+    timestamp_format: TimestampFormat,
+    clock: Box<dyn Clock + Send + Sync>,
+    insert_ids: Option<InsertIdGenerator>,
+    error_reporting: Option<ServiceContext>,
+    target_placement: TargetPlacement,
+    resource: Option<MonitoredResource>,
+    with_span_timing: bool,
+    pretty: bool,
+    #[cfg(feature = "opentelemetry")]
+    with_opentelemetry: bool,
+    #[cfg(feature = "opentelemetry")]
+    with_baggage_labels: bool,
+    #[cfg(feature = "protobuf")]
+    protobuf: bool,
+    message_key: String,
+    with_thread_labels: bool,
+    payload_format: PayloadFormat,
+    span_sampling: Option<SpanSampling>,
+    log_name: Option<String>,
+    report_threshold: Level,
+    field_filter: FieldFilter,
+    dynamic_labels: Option<Box<dyn Fn() -> HashMap<String, String> + Send + Sync>>,
+    on_error: OnError,
+    span_name_labels: SpanNameLabels,
+    max_value_len: Option<usize>,
+    with_correlation_ids: bool,
+    entry_size_limit: (usize, OversizedEntry),
+    time_key: Option<String>,
+    numeric_severity: bool,
+    field_budget: Option<FieldBudget>,
+    version: Option<String>,
+    record_delimiter: u8,
+    skip_fields: Option<SkipFields>,
+    entry_keys: EntryKeys,
+}
+
+/// The JSON file read by [`GcpLayerBuilder::from_config_path`].
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct LayerConfig {
+    project_id: Option<String>,
+    default_labels: HashMap<String, String>,
+    service: Option<String>,
+    version: Option<String>,
+}
+
+impl GcpLayerBuilder {
+    /// Starts from a JSON file such as `{"project_id": "p", "default_labels": {"team":
+    /// "t"}, "service": "s", "version": "1"}`, where every member is optional.
+    /// Without `project_id`, the project is detected with [`detect_project_id`].
+    /// Without `service` and `version`, the Cloud Run `K_SERVICE` and `K_REVISION` env
+    /// vars are used; a service enables [`error_reporting`](Self::error_reporting).
+    pub async fn from_config_path(path: impl AsRef<Path>) -> io::Result<Self> {
+        let config: LayerConfig = serde_json::from_slice(&std::fs::read(path)?)?;
+        let mut builder = GcpLayer::builder().default_labels(config.default_labels);
+        if let Some(project_id) = config.project_id.or(detect_project_id().await) {
+            builder = builder.project_id(project_id);
+        }
+        let service = config.service.or_else(|| env::var("K_SERVICE").ok());
+        if let Some(service) = service {
+            let version = config.version.or_else(|| env::var("K_REVISION").ok());
+            builder = builder.error_reporting(ServiceContext { service, version });
+        }
+        Ok(builder)
+    }
+}
+
+impl<W> GcpLayerBuilder<W> {
+    pub fn project_id(mut self, gcp_project_id: impl Into<String>) -> Self {
+        self.gcp_project_id = Some(gcp_project_id.into());
+        self
+    }
+
+    /// Adds `logging.googleapis.com/sourceLocation` to every entry.
+    pub fn with_source_location(mut self, with_source_location: bool) -> Self {
+        self.with_source_location = with_source_location;
+        self
+    }
+
+    /// Writes entries to `writer`, stderr by default. Each entry is serialized whole
+    /// and written with a single `write_all`: wrap writers that don't lock around it,
+    /// such as a `File`, in a [`gcp_logging_core::LockedWriter`] so that concurrent
+    /// entries don't interleave.
+    pub fn writer<W2>(self, writer: W2) -> GcpLayerBuilder<W2>
+    where
+        W2: for<'writer> MakeWriter<'writer> + 'static,
+    {
+        GcpLayerBuilder {
+            gcp_project_id: self.gcp_project_id,
+            with_source_location: self.with_source_location,
+            writer,
+            default_labels: self.default_labels,
+            timestamp_format: self.timestamp_format,
+            clock: self.clock,
+            insert_ids: self.insert_ids,
+            error_reporting: self.error_reporting,
+            target_placement: self.target_placement,
+            resource: self.resource,
+            with_span_timing: self.with_span_timing,
+            pretty: self.pretty,
+            #[cfg(feature = "opentelemetry")]
+            with_opentelemetry: self.with_opentelemetry,
+            #[cfg(feature = "opentelemetry")]
+            with_baggage_labels: self.with_baggage_labels,
+            #[cfg(feature = "protobuf")]
+            protobuf: self.protobuf,
+            message_key: self.message_key,
+            with_thread_labels: self.with_thread_labels,
+            payload_format: self.payload_format,
+            span_sampling: self.span_sampling,
+            log_name: self.log_name,
+            report_threshold: self.report_threshold,
+            field_filter: self.field_filter,
+            dynamic_labels: self.dynamic_labels,
+            on_error: self.on_error,
+            span_name_labels: self.span_name_labels,
+            max_value_len: self.max_value_len,
+            with_correlation_ids: self.with_correlation_ids,
+            entry_size_limit: self.entry_size_limit,
+            time_key: self.time_key,
+            numeric_severity: self.numeric_severity,
+            field_budget: self.field_budget,
+            version: self.version,
+            record_delimiter: self.record_delimiter,
+            skip_fields: self.skip_fields,
+            entry_keys: self.entry_keys,
+        }
+    }
+
+    /// Writes entries at `threshold` and above to `at_or_above`, e.g. ERROR entries to
+    /// stderr, and the others to `below`.
+    pub fn split_writer<A, B>(
+        self,
+        threshold: Level,
+        at_or_above: A,
+        below: B,
+    ) -> GcpLayerBuilder<OrElse<WithMaxLevel<A>, B>>
+    where
+        A: for<'writer> MakeWriter<'writer> + 'static,
+        B: for<'writer> MakeWriter<'writer> + 'static,
+    {
+        self.writer(at_or_above.with_max_level(threshold).or_else(below))
+    }
+
+    /// Labels added to `logging.googleapis.com/labels` on every entry. Span and event
+    /// fields named `label.<key>` add to or override them.
+    pub fn default_labels(mut self, default_labels: HashMap<String, String>) -> Self {
+        self.default_labels = default_labels;
+        self
+    }
+
+    /// Writes `timestamp` as seconds and nanos instead of the default RFC3339 `time`.
+    pub fn timestamp_format(mut self, timestamp_format: TimestampFormat) -> Self {
+        self.timestamp_format = timestamp_format;
+        self
+    }
+
+    pub fn clock(mut self, clock: impl Clock + Send + Sync + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Adds a unique `logging.googleapis.com/insertId` to every entry.
+    pub fn with_insert_id(mut self, with_insert_id: bool) -> Self {
+        self.insert_ids = with_insert_id.then(InsertIdGenerator::new);
+        self
+    }
+
+    /// Marks ERROR entries as Error Reporting events of `service_context`, or those at
+    /// the [`report_threshold`](Self::report_threshold) and above.
+    pub fn error_reporting(mut self, service_context: ServiceContext) -> Self {
+        self.error_reporting = Some(service_context);
+        self
+    }
+
+    /// Records each event's target as a label or a payload member.
+    pub fn target_placement(mut self, target_placement: TargetPlacement) -> Self {
+        self.target_placement = target_placement;
+        self
+    }
+
+    /// Sets the `resource` entries are attached to, e.g.
+    /// [`MonitoredResource::detect_cloud_run`]. None, the default, omits it.
+    pub fn resource(mut self, resource: Option<MonitoredResource>) -> Self {
+        self.resource = resource;
+        self
+    }
+
+    /// Writes an entry with the span's `elapsed_ms` when it closes, also setting the
+    /// `latency` of its `httpRequest`.
+    pub fn with_span_timing(mut self, with_span_timing: bool) -> Self {
+        self.with_span_timing = with_span_timing;
+        self
+    }
+
+    /// Indents entries over several lines, for reading logs locally. Cloud Logging
+    /// needs the default single-line entries.
+    pub fn pretty(mut self, pretty: bool) -> Self {
+        self.pretty = pretty;
+        self
+    }
+
+    /// Correlates entries using the ids of the OpenTelemetry spans recorded by
+    /// `tracing_opentelemetry::OpenTelemetryLayer`, when no span sets a `trace_id`.
+    #[cfg(feature = "opentelemetry")]
+    pub fn with_opentelemetry(mut self, with_opentelemetry: bool) -> Self {
+        self.with_opentelemetry = with_opentelemetry;
+        self
+    }
+
+    /// Adds the OpenTelemetry baggage of the current `Context`, and of the contexts
+    /// spans were given with `OpenTelemetrySpanExt::set_parent`, to the labels of
+    /// entries. Labels set by fields take precedence.
+    #[cfg(feature = "opentelemetry")]
+    pub fn with_baggage_labels(mut self, with_baggage_labels: bool) -> Self {
+        self.with_baggage_labels = with_baggage_labels;
+        self
+    }
+
+    /// Writes entries as `google.logging.v2.LogEntry` protobuf messages, each prefixed
+    /// with its varint length, instead of JSON lines. See [`LogEntry::to_protobuf`].
+    #[cfg(feature = "protobuf")]
+    pub fn protobuf(mut self, protobuf: bool) -> Self {
+        self.protobuf = protobuf;
+        self
+    }
+
+    /// Puts the message under `message_key` instead of `message`, e.g. `textPayload`.
+    pub fn message_key(mut self, message_key: impl Into<String>) -> Self {
+        self.message_key = message_key.into();
+        self
+    }
+
+    /// Adds the `thread.id`, `thread.name` and `process.pid` labels to every entry.
+    pub fn with_thread_labels(mut self, with_thread_labels: bool) -> Self {
+        self.with_thread_labels = with_thread_labels;
+        self
+    }
+
+    /// Writes entries with nothing but a message as plain text, see [`PayloadFormat`].
+    pub fn payload_format(mut self, payload_format: PayloadFormat) -> Self {
+        self.payload_format = payload_format;
+        self
+    }
+
+    /// Keeps the events of spans that have a `sample` field, or are named in
+    /// `span_names`, with probability `probability`. The decision is made once per span
+    /// and covers the spans inside it; ERROR events are always kept.
+    pub fn sample_spans(
+        mut self,
+        probability: f64,
+        span_names: impl IntoIterator<Item = &'static str>,
+    ) -> Self {
+        let span_names = span_names.into_iter().collect();
+        self.span_sampling = Some(SpanSampling { probability, span_names });
+        self
+    }
+
+    /// Routes entries to the log `log_id` of the project rather than the default one.
+    pub fn log_name(mut self, log_id: impl Into<String>) -> Self {
+        self.log_name = Some(log_id.into());
+        self
+    }
+
+    /// Marks events at `level` or above as Error Reporting events once
+    /// [`error_reporting`](Self::error_reporting) is set, instead of only ERROR ones.
+    pub fn report_threshold(mut self, level: Level) -> Self {
+        self.report_threshold = level;
+        self
+    }
+
+    /// Redacts or drops the span and event fields named by `field_filter`.
+    pub fn field_filter(mut self, field_filter: FieldFilter) -> Self {
+        self.field_filter = field_filter;
+        self
+    }
+
+    /// Calls `dynamic_labels` for every entry and adds the labels it returns, e.g. the
+    /// current tenant, over the [`default_labels`](Self::default_labels).
+    pub fn dynamic_labels(
+        mut self,
+        dynamic_labels: impl Fn() -> HashMap<String, String> + Send + Sync + 'static,
+    ) -> Self {
+        self.dynamic_labels = Some(Box::new(dynamic_labels));
+        self
+    }
+
+    /// Calls `on_error` when an entry can't be serialized or written, e.g. to count
+    /// logging failures. Defaults to [`eprint_log_error`].
+    pub fn on_error(
+        mut self,
+        on_error: impl Fn(&LogError) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_error = Box::new(on_error);
+        self
+    }
+
+    /// Records the names of the spans an event is in as labels.
+    pub fn span_name_labels(mut self, span_name_labels: SpanNameLabels) -> Self {
+        self.span_name_labels = span_name_labels;
+        self
+    }
+
+    /// Truncates the message and string fields longer than `max_value_len` bytes, see
+    /// [`truncate_values`].
+    pub fn max_value_len(mut self, max_value_len: usize) -> Self {
+        self.max_value_len = Some(max_value_len);
+        self
+    }
+
+    /// Gives root spans without a `correlation_id` field a random one, see
+    /// [`new_correlation_id`], so that every entry logged inside them has the same
+    /// `correlation_id` label.
+    pub fn with_correlation_ids(mut self, with_correlation_ids: bool) -> Self {
+        self.with_correlation_ids = with_correlation_ids;
+        self
+    }
+
+    /// Handles entries over `max_size` bytes as `oversized` says. By default, entries
+    /// over Cloud Logging's [`MAX_ENTRY_SIZE`] are replaced with a warning.
+    pub fn entry_size_limit(
+        mut self,
+        max_size: usize,
+        oversized: OversizedEntry,
+    ) -> Self {
+        self.entry_size_limit = (max_size, oversized);
+        self
+    }
+
+    /// Puts the time under `time_key` instead of `time` (or `timestamp`).
+    pub fn time_key(mut self, time_key: impl Into<String>) -> Self {
+        self.time_key = Some(time_key.into());
+        self
+    }
+
+    /// Writes `severity` as its `LogSeverity` number, e.g. 400 for WARNING, instead of
+    /// its name. See [`LogEntry::use_numeric_severity`].
+    pub fn numeric_severity(mut self, numeric_severity: bool) -> Self {
+        self.numeric_severity = numeric_severity;
+        self
+    }
+
+    /// Writes the special members under `entry_keys`, e.g. [`EntryKeys::Generic`] for
+    /// plain `trace`, `span_id` and `labels` keys outside of GCP.
+    pub fn entry_keys(mut self, entry_keys: EntryKeys) -> Self {
+        self.entry_keys = entry_keys;
+        self
+    }
+
+    /// Drops fields once they take more than the budget, see [`FieldBudget`].
+    pub fn field_budget(mut self, field_budget: FieldBudget) -> Self {
+        self.field_budget = Some(field_budget);
+        self
+    }
+
+    /// Adds a `version` label, e.g. from [`gcp_logging_core::service_version_from_env`].
+    /// None, the default, omits it. Reported errors whose `ServiceContext` has no
+    /// version get it too.
+    pub fn version(mut self, version: Option<String>) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Ends entries with `record_delimiter` rather than a newline, e.g. `b'\0'` for
+    /// collectors that read NUL-delimited JSON. Protobuf entries are length-delimited
+    /// instead.
+    pub fn record_delimiter(mut self, record_delimiter: u8) -> Self {
+        self.record_delimiter = record_delimiter;
+        self
+    }
+
+    /// Drops the fields for which `skip_fields` returns true given their name and value,
+    /// e.g. values that look like tokens, which [`field_filter`](Self::field_filter)
+    /// can't tell by name.
+    pub fn skip_fields(
+        mut self,
+        skip_fields: impl Fn(&str, &Value) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.skip_fields = Some(Box::new(skip_fields));
+        self
+    }
+
+    /// Falls back to the `GOOGLE_CLOUD_PROJECT` env var when no project id was set.
+    pub fn build(mut self) -> Result<GcpLayer<W>, MissingProjectId> {
+        let gcp_project_id =
+            self.gcp_project_id.or_else(project_id_from_env).ok_or(MissingProjectId)?;
+        if let Some(version) = self.version {
+            if let Some(service_context) = &mut self.error_reporting {
+                service_context.version.get_or_insert_with(|| version.clone());
+            }
+            self.default_labels.entry("version".into()).or_insert(version);
+        }
+        let log_name = self.log_name.map(|id| format_log_name(&gcp_project_id, &id));
+        Ok(GcpLayer {
+            trace_prefix: trace_prefix(&gcp_project_id),
+            with_source_location: self.with_source_location,
+            writer: self.writer,
+            default_labels: self.default_labels,
+            timestamp_format: self.timestamp_format,
+            clock: self.clock,
+            insert_ids: self.insert_ids,
+            error_reporting: self.error_reporting,
+            target_placement: self.target_placement,
+            resource: self.resource,
+            with_span_timing: self.with_span_timing,
+            #[cfg(feature = "opentelemetry")]
+            with_opentelemetry: self.with_opentelemetry,
+            #[cfg(feature = "opentelemetry")]
+            with_baggage_labels: self.with_baggage_labels,
+            #[cfg(feature = "protobuf")]
+            protobuf: self.protobuf,
+            message_key: self.message_key,
+            with_thread_labels: self.with_thread_labels,
+            span_sampling: self.span_sampling,
+            log_name,
+            report_threshold: self.report_threshold,
+            field_filter: self.field_filter,
+            dynamic_labels: self.dynamic_labels,
+            on_error: self.on_error,
+            span_name_labels: self.span_name_labels,
+            max_value_len: self.max_value_len,
+            with_correlation_ids: self.with_correlation_ids,
+            time_key: self.time_key,
+            field_budget: self.field_budget,
+            skip_fields: self.skip_fields,
+            write_options: WriteOptions {
+                pretty: self.pretty,
+                numeric_severity: self.numeric_severity,
+                record_delimiter: self.record_delimiter,
+                payload_format: self.payload_format,
+                entry_size_limit: self.entry_size_limit,
+                entry_keys: self.entry_keys,
+            },
+        })
+    }
+}
+
+/// Which names of the spans an event is in [`GcpLayer`] records as labels.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum SpanNameLabels {
+    #[default]
+    Omit,
+    /// The name of the innermost span, as the `span.name` label.
+    Innermost,
+    /// The names of all the spans from the root, joined with `/`, as the `spans` label.
+    Path,
+}
+
+impl<W> GcpLayer<W>
+where
+    W: for<'writer> MakeWriter<'writer> + 'static,
+{
+    /// Writes `entry` to the writer for `metadata`, that of the event or span it was
+    /// made for.
+    fn write(&self, metadata: &Metadata<'_>, entry: &mut LogEntry) {
+        #[cfg(feature = "protobuf")]
+        if self.protobuf {
+            let entry = entry.to_length_delimited_protobuf();
+            if let Err(error) = self.writer.make_writer_for(metadata).write_all(&entry) {
+                (self.on_error)(&error.into());
+            }
+            return;
+        }
+        let mut writer = self.writer.make_writer_for(metadata);
+        if let Err(error) = write_entry(&mut writer, entry, self.write_options) {
+            (self.on_error)(&error);
+        }
+    }
+
+    /// The name of the service: that of the Error Reporting `ServiceContext`, or else
+    /// the Cloud Run `K_SERVICE` env var.
+    fn service(&self) -> Option<String> {
+        match &self.error_reporting {
+            Some(service_context) => Some(service_context.service.clone()),
+            None => env::var("K_SERVICE").ok(),
+        }
+    }
+
+    /// The default labels, with those of the `dynamic_labels` closure over them.
+    fn labels(&self) -> HashMap<String, String> {
+        let mut labels = self.default_labels.clone();
+        if let Some(dynamic_labels) = &self.dynamic_labels {
+            labels.extend(dynamic_labels());
+        }
+        labels
+    }
+}
+
+impl<S, W> Layer<S> for GcpLayer<W>
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+    W: for<'writer> MakeWriter<'writer> + 'static,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if self.with_span_timing {
+                span.extensions_mut().insert(SpanStart(Instant::now()));
+            }
+            let mut visitor = FieldExtractor::new(SPAN_METADATA_FIELDS);
+            attrs.record(&mut visitor);
+            let is_operation = match visitor.extracted.remove("operation") {
+                Some(Value::Bool(is_operation)) => is_operation,
+                // Not the marker, but a payload field that happens to be named so.
+                Some(value) => {
+                    visitor.fields.insert("operation".into(), value);
+                    false
+                }
+                None => false,
+            };
+            let id = visitor.take_string("operation_id");
+            if let Some(id) = id.or_else(|| is_operation.then(new_operation_id)) {
+                let producer =
+                    visitor.take_string("operation_producer").or_else(|| self.service());
+                let first_emitted = AtomicBool::new(false);
+                let operation = SpanOperation { id, producer, first_emitted };
+                span.extensions_mut().insert(Arc::new(operation));
+            }
+            if let Some(id) = visitor.take_trace_id() {
+                let sampled = !matches!(
+                    visitor.extracted.get("trace_sampled"),
+                    Some(Value::Bool(false))
+                );
+                let project = visitor.take_string("trace_project");
+                span.extensions_mut().insert(TraceId { id, sampled, project });
+            }
+            if let Some(span_id) = visitor.take_string("span_id") {
+                span.extensions_mut().insert(SpanId(span_id));
+            }
+            let correlation_id = visitor.take_string("correlation_id").or_else(|| {
+                (self.with_correlation_ids && span.parent().is_none())
+                    .then(new_correlation_id)
+            });
+            if let Some(correlation_id) = correlation_id {
+                span.extensions_mut().insert(CorrelationId(correlation_id));
+            }
+            if let Some(floor) = visitor.take_severity("min_severity") {
+                span.extensions_mut().insert(MinSeverity(floor));
+            }
+            let sample = !matches!(
+                visitor.extracted.get("sample"),
+                None | Some(Value::Bool(false))
+            );
+            if let Some(sampling) = &self.span_sampling
+                && (sample || sampling.span_names.contains(&span.name()))
+                && !sampling.keeps()
+            {
+                span.extensions_mut().insert(SampledOut);
+            }
+            if !visitor.fields.is_empty() {
+                span.extensions_mut().insert(SpanFields(visitor.fields));
+            }
+        };
+    }
+
+    /// Picks up fields recorded after the span was created, such as a `trace_id`
+    /// only known once a request's headers are read. Operations and sampling are
+    /// decided when the span is created and don't change.
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut visitor = FieldExtractor::new(SPAN_METADATA_FIELDS);
+        values.record(&mut visitor);
+        let mut extensions = span.extensions_mut();
+        let sampled = match visitor.extracted.get("trace_sampled") {
+            Some(Value::Bool(sampled)) => Some(*sampled),
+            _ => None,
+        };
+        let project = visitor.take_string("trace_project");
+        if let Some(id) = visitor.take_trace_id() {
+            let previous = extensions.remove::<TraceId>();
+            let sampled = sampled.unwrap_or(previous.as_ref().is_none_or(|t| t.sampled));
+            let project = project.or(previous.and_then(|t| t.project));
+            extensions.insert(TraceId { id, sampled, project });
+        } else if let Some(trace_id) = extensions.get_mut::<TraceId>() {
+            trace_id.sampled = sampled.unwrap_or(trace_id.sampled);
+            trace_id.project = project.or(trace_id.project.take());
+        }
+        if let Some(span_id) = visitor.take_string("span_id") {
+            extensions.replace(SpanId(span_id));
+        }
+        if let Some(correlation_id) = visitor.take_string("correlation_id") {
+            extensions.replace(CorrelationId(correlation_id));
+        }
+        if let Some(floor) = visitor.take_severity("min_severity") {
+            extensions.replace(MinSeverity(floor));
+        }
+        if !visitor.fields.is_empty() {
+            match extensions.get_mut::<SpanFields>() {
+                Some(SpanFields(fields)) => fields.extend(visitor.fields),
+                None => extensions.insert(SpanFields(visitor.fields)),
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        // Read the clock first: the entry carries the time of the event, not that of
+        // the (possibly delayed) write.
+        let now = self.clock.now();
+        let mut trace = None;
+        let mut span_id = None;
+        let mut trace_sampled = None;
+        let mut http_request = None;
+        let mut operation = None;
+        let mut fields = Map::new();
+        let mut innermost = None;
+        let mut span_names = Vec::new();
+        let mut correlation_id = None;
+        let mut min_severity = None;
+        #[cfg(feature = "opentelemetry")]
+        let mut otel = None;
+        #[cfg(feature = "opentelemetry")]
+        let mut baggage = HashMap::new();
+        // Outside of any span, the entry gets no trace, spanId or span fields.
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                innermost = Some(span.id());
+                span_names.push(span.name());
+                let extensions = span.extensions();
+                if extensions.get::<SampledOut>().is_some()
+                    && *event.metadata().level() != Level::ERROR
+                {
+                    return;
+                }
+                if let Some(SpanFields(span_fields)) = extensions.get::<SpanFields>() {
+                    fields.extend(span_fields.clone());
+                }
+                if let Some(o) = extensions.get::<Arc<SpanOperation>>() {
+                    operation = Some(o.clone());
+                }
+                if let Some(r) = extensions.get::<HttpRequest>() {
+                    http_request = Some(r.clone());
+                }
+                if let Some(s) = extensions.get::<SpanId>() {
+                    span_id = Some(s.0.clone());
+                }
+                if let Some(CorrelationId(id)) = extensions.get::<CorrelationId>() {
+                    correlation_id = Some(id.clone());
+                }
+                if let Some(MinSeverity(floor)) = extensions.get::<MinSeverity>() {
+                    min_severity =
+                        Some(max_severity(min_severity.unwrap_or(floor), floor));
+                }
+                // The outermost trace_id wins: nested spans belong to the trace of the
+                // request that contains them.
+                if trace.is_none()
+                    && let Some(trace_id) = extensions.get::<TraceId>()
+                {
+                    trace = trace_id.format(&self.trace_prefix);
+                    trace_sampled = trace.as_ref().map(|_| trace_id.sampled);
+                }
+                #[cfg(feature = "opentelemetry")]
+                if self.with_opentelemetry
+                    && let Some(data) =
+                        extensions.get::<tracing_opentelemetry::OtelData>()
+                {
+                    otel = otel_ids(data);
+                }
+                #[cfg(feature = "opentelemetry")]
+                if self.with_baggage_labels
+                    && let Some(data) =
+                        extensions.get::<tracing_opentelemetry::OtelData>()
+                {
+                    add_baggage_labels(&data.parent_cx, &mut baggage);
+                }
+            }
+        }
+        #[cfg(feature = "opentelemetry")]
+        if trace.is_none()
+            && let Some((trace_id, otel_span_id, sampled)) = otel
+        {
+            trace = format_trace_with_prefix(&self.trace_prefix, &trace_id);
+            trace_sampled = Some(sampled);
+            span_id = span_id.or(Some(otel_span_id));
+        }
+        #[cfg(feature = "opentelemetry")]
+        if self.with_baggage_labels {
+            add_baggage_labels(&opentelemetry::Context::current(), &mut baggage);
+        }
+        span_id = span_id.or_else(|| innermost.as_ref().map(tracing_id_to_gcp_span_id));
+        let mut visitor = FieldExtractor::new(EVENT_METADATA_FIELDS);
+        event.record(&mut visitor);
+        let severity = visitor
+            .take_severity("severity")
+            .unwrap_or_else(|| level_to_gcp_severity(event.metadata().level()));
+        let severity = match min_severity {
+            Some(floor) => max_severity(severity, floor),
+            None => severity,
+        };
+        let mut message = visitor.take_string("message").unwrap_or_default();
+        fields.extend(visitor.fields);
+        self.field_filter.apply(&mut fields);
+        if let Some(skip_fields) = &self.skip_fields {
+            fields.retain(|name, value| !skip_fields(name, value));
+        }
+        if let Some(max_len) = self.max_value_len {
+            truncate_values(&mut message, &mut fields, max_len);
+        }
+        let mut labels = self.labels();
+        if self.with_thread_labels {
+            add_thread_labels(&mut labels);
+        }
+        #[cfg(feature = "opentelemetry")]
+        labels.extend(baggage);
+        if let Some(correlation_id) = correlation_id {
+            labels.insert("correlation_id".into(), correlation_id);
+        }
+        match self.span_name_labels {
+            SpanNameLabels::Omit => {}
+            SpanNameLabels::Innermost => {
+                if let Some(name) = span_names.last() {
+                    labels.insert("span.name".into(), (*name).into());
+                }
+            }
+            SpanNameLabels::Path => {
+                if !span_names.is_empty() {
+                    labels.insert("spans".into(), span_names.join("/"));
+                }
+            }
+        }
+        extract_labels(&mut fields, &mut labels);
+        if merge_json_payload(&mut fields) {
+            message.clear();
+        }
+        let metadata = event.metadata();
+        self.target_placement.place(metadata.target(), &mut labels, &mut fields);
+        if let Some(field_budget) = &self.field_budget {
+            field_budget.apply(&mut fields);
+        }
+        let reported_error = self
+            .error_reporting
+            .as_ref()
+            .filter(|_| *metadata.level() <= self.report_threshold);
+        let source_location = self.with_source_location.then(|| SourceLocation {
+            file: metadata.file(),
+            line: metadata.line(),
+            function: metadata.module_path().or(Some(metadata.target())),
+        });
+        let mut entry = LogEntry {
+            severity,
+            message,
+            time: Timestamp::new(now, self.timestamp_format),
+            trace,
+            span_id,
+            trace_sampled,
+            source_location,
+            http_request,
+            insert_id: self.insert_ids.as_ref().map(InsertIdGenerator::next_id),
+            log_name: self.log_name.as_deref(),
+            error_type: reported_error.map(|_| REPORTED_ERROR_EVENT_TYPE),
+            service_context: reported_error,
+            operation: operation.map(|o| o.for_event()),
+            resource: self.resource.as_ref(),
+            labels,
+            fields,
+            message_key: None,
+            time_key: None,
+        };
+        entry.rename_message(&self.message_key);
+        if let Some(time_key) = &self.time_key {
+            entry.rename_time(time_key);
+        }
+        self.write(metadata, &mut entry);
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let now = self.clock.now();
+        let Some(span) = ctx.span(&id) else { return };
+        let extensions = span.extensions();
+        let op = extensions.get::<Arc<SpanOperation>>();
+        let start = extensions.get::<SpanStart>();
+        if op.is_none() && start.is_none() {
+            return;
+        }
+        let severity = level_to_gcp_severity(span.metadata().level());
+        let time = Timestamp::new(now, self.timestamp_format);
+        let mut entry = LogEntry::new(severity, String::new(), time);
+        entry.trace = span
+            .scope()
+            .from_root()
+            .find_map(|s| s.extensions().get::<TraceId>()?.format(&self.trace_prefix));
+        entry.span_id = Some(match extensions.get::<SpanId>() {
+            Some(SpanId(span_id)) => span_id.clone(),
+            None => tracing_id_to_gcp_span_id(&id),
+        });
+        entry.operation = op.map(|op| Operation {
+            id: op.id.clone(),
+            producer: op.producer.clone(),
+            first: false,
+            last: true,
+        });
+        if let Some(SpanStart(start)) = start {
+            let elapsed = start.elapsed();
+            entry.fields.insert("span".into(), span.name().into());
+            entry
+                .fields
+                .insert("elapsed_ms".into(), (elapsed.as_secs_f64() * 1000.0).into());
+            entry.http_request = extensions.get::<HttpRequest>().map(|r| HttpRequest {
+                latency: Some(format!("{:.9}s", elapsed.as_secs_f64())),
+                ..r.clone()
+            });
+        }
+        entry.insert_id = self.insert_ids.as_ref().map(InsertIdGenerator::next_id);
+        entry.log_name = self.log_name.as_deref();
+        entry.resource = self.resource.as_ref();
+        entry.labels = self.labels();
+        // The `label.` fields of the span and its parents, as for its events.
+        for s in span.scope().from_root() {
+            if let Some(SpanFields(fields)) = s.extensions().get::<SpanFields>() {
+                extract_labels(&mut fields.clone(), &mut entry.labels);
+            }
+        }
+        let correlation_id = span
+            .scope()
+            .find_map(|s| Some(s.extensions().get::<CorrelationId>()?.0.clone()));
+        if let Some(correlation_id) = correlation_id {
+            entry.labels.insert("correlation_id".into(), correlation_id);
+        }
+        if let Some(time_key) = &self.time_key {
+            entry.rename_time(time_key);
+        }
+        self.write(span.metadata(), &mut entry);
+    }
+}
+
+/// Builds the layer `builder` configures, filtered by [`env_filter`], without
+/// installing it, for composing with other layers:
+/// `registry().with(gcp_layer(builder)?.0).with(other).init()`. The handle swaps the
+/// filter at runtime.
+pub fn gcp_layer<S, W>(
+    builder: GcpLayerBuilder<W>,
+) -> Result<(impl Layer<S>, reload::Handle<EnvFilter, S>), MissingProjectId>
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+    W: for<'writer> MakeWriter<'writer> + 'static,
+{
+    let layer = builder.build()?;
+    let (filter, filter_handle) = reload::Layer::new(env_filter());
+    Ok((layer.with_filter(filter), filter_handle))
+}
+
+/// The layer [`auto_layer`] picks.
+pub type AutoLayer<S> = Box<dyn Layer<S> + Send + Sync>;
+
+/// Like [`gcp_layer`] when [`running_on_gcp`], and otherwise a human-readable
+/// `tracing_subscriber::fmt` layer writing to the writer of `builder`, for local
+/// development. Off GCP, `builder` needs no project id.
+pub fn auto_layer<S, W>(
+    builder: GcpLayerBuilder<W>,
+) -> Result<(AutoLayer<S>, reload::Handle<EnvFilter, S>), MissingProjectId>
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    if running_on_gcp() {
+        let (layer, filter_handle) = gcp_layer(builder)?;
+        return Ok((layer.boxed(), filter_handle));
+    }
+    let layer = tracing_subscriber::fmt::layer().pretty().with_writer(builder.writer);
+    let (filter, filter_handle) = reload::Layer::new(env_filter());
+    Ok((layer.with_filter(filter).boxed(), filter_handle))
+}
+
+/// Installs [`auto_layer`] as the global subscriber: Cloud Logging JSON on GCP, and
+/// human-readable entries elsewhere. The handle swaps the filter at runtime.
+///
+/// Panics if a global subscriber is already installed.
+pub fn init_auto<W>(
+    builder: GcpLayerBuilder<W>,
+) -> Result<reload::Handle<EnvFilter, Registry>, MissingProjectId>
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let (layer, filter_handle) = auto_layer(builder)?;
+    tracing_subscriber::registry().with(layer).init();
+    Ok(filter_handle)
+}
+
+/// Reads `EnvFilter` directives from `RUST_LOG`, or else `LOG_LEVEL`, defaulting to
+/// INFO. Invalid directives are ignored.
+///
+/// Per-target directives are honored: `sqlx=warn,my_app=debug` drops the DEBUG and
+/// INFO events of `sqlx` while keeping those of `my_app`. The filter sits in front of
+/// the `GcpLayer`, so filtered out events are never formatted.
+pub fn env_filter() -> EnvFilter {
+    let var = if env::var_os("RUST_LOG").is_some() {
+        "RUST_LOG"
+    } else {
+        "LOG_LEVEL"
+    };
+    EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .with_env_var(var)
+        .from_env_lossy()
+}
+
+/// Formats a span's `Id` as a `logging.googleapis.com/spanId`: 16 lowercase hex
+/// digits. Entries get the id of their innermost span unless a span sets `span_id`.
+pub fn tracing_id_to_gcp_span_id(id: &Id) -> String {
+    format!("{:016x}", id.into_u64())
+}
+
+/// Attaches `http_request` to `span` so that events inside it carry an `httpRequest`.
+/// The innermost span with a request wins.
+pub fn record_http_request(span: &Span, http_request: HttpRequest) {
+    span.with_subscriber(|(id, dispatch)| {
+        let Some(registry) = dispatch.downcast_ref::<Registry>() else { return };
+        if let Some(span) = registry.span(id) {
+            span.extensions_mut().replace(http_request);
+        }
+    });
+}
+
+/// The trace id the entries logged here get, e.g. to propagate it in the headers of
+/// outbound requests: the one of the current span, as set by a `trace_id` field of it
+/// or of a span containing it, or else the one of the task, see
+/// [`gcp_logging_core::current_trace_id`]. As for the entries, the outermost valid
+/// `trace_id` wins and it is lowercased.
+pub fn current_trace_id() -> Option<String> {
+    Span::current()
+        .with_subscriber(|(id, dispatch)| {
+            let registry = dispatch.downcast_ref::<Registry>()?;
+            registry.span(id)?.scope().from_root().find_map(|span| {
+                normalize_trace_id(&span.extensions().get::<TraceId>()?.id)
+            })
+        })
+        .flatten()
+        .or_else(gcp_logging_core::current_trace_id)
+}
+
+/// Writes one access-log style entry for a finished request, with `http_request` as
+/// its `httpRequest` and the trace of the current span. The request is attached to the
+/// current span as by [`record_http_request`], or to a new `request` span outside of
+/// any. See [`request_summary_level`] for the severity.
+pub fn log_request_summary(http_request: HttpRequest) {
+    let level = request_summary_level(http_request.status);
+    let message = [
+        http_request.request_method.as_deref(),
+        http_request.request_url.as_deref(),
+        http_request.status.map(|s| s.to_string()).as_deref(),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>()
+    .join(" ");
+    let span = Span::current();
+    let span = if span.is_none() {
+        tracing::info_span!("request")
+    } else {
+        span
+    };
+    record_http_request(&span, http_request);
+    span.in_scope(|| match level {
+        Level::ERROR => tracing::error!("{message}"),
+        Level::WARN => tracing::warn!("{message}"),
+        _ => tracing::info!("{message}"),
+    });
+}
+
+/// The level of a request summary: ERROR for 5xx statuses, WARN for 4xx ones and INFO
+/// otherwise.
+pub fn request_summary_level(status: Option<u16>) -> Level {
+    match status {
+        Some(500..) => Level::ERROR,
+        Some(400..=499) => Level::WARN,
+        _ => Level::INFO,
+    }
+}
+
+/// Like [`with_trace_id`](fn@with_trace_id), with the `trace_id` span created at the
+/// call site, so that it has the caller's target: `with_trace_id!(trace_id, future)`.
+#[macro_export]
+macro_rules! with_trace_id {
+    ($trace_id:expr, $future:expr $(,)?) => {{
+        let trace_id = ::std::string::ToString::to_string(&$trace_id);
+        $crate::__tracing::Instrument::instrument(
+            $crate::__TASK_LOCAL_TRACE_ID.scope(Some(trace_id.clone()), $future),
+            $crate::__tracing::info_span!("trace_id", trace_id = %trace_id),
+        )
+    }};
+}
+
+#[doc(hidden)]
+pub use gcp_logging_core::TASK_LOCAL_TRACE_ID as __TASK_LOCAL_TRACE_ID;
+#[doc(hidden)]
+pub use tracing as __tracing;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::Mutex;
+    use tracing::info;
+
+    /// Records the fields of each event with a `FieldExtractor`.
+    #[derive(Clone, Default)]
+    struct Extracting(Arc<Mutex<Vec<FieldExtractor>>>);
+
+    impl<S: Subscriber> Layer<S> for Extracting {
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            let mut extractor = FieldExtractor::new(EVENT_METADATA_FIELDS);
+            event.record(&mut extractor);
+            self.0.lock().unwrap().push(extractor);
+        }
+    }
+
+    #[test]
+    fn sets_aside_the_wanted_fields_in_one_pass() {
+        let layer = Extracting::default();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            info!(count = 3, severity = "NOTICE", user = "alice", "extracted");
+        });
+        let mut extractors = layer.0.lock().unwrap();
+        let extractor = &mut extractors[0];
+        assert_eq!(extractor.take_severity("severity"), Some("NOTICE"));
+        assert_eq!(extractor.take_string("message").as_deref(), Some("extracted"));
+        assert!(extractor.extracted.is_empty());
+        assert_eq!(
+            Value::Object(extractor.fields.clone()),
+            json!({ "count": 3, "user": "alice" })
+        );
+    }
+
+    #[test]
+    fn formats_span_ids_as_zero_padded_lowercase_hex() {
+        assert_eq!(tracing_id_to_gcp_span_id(&Id::from_u64(0xAB)), "00000000000000ab");
+        assert_eq!(
+            tracing_id_to_gcp_span_id(&Id::from_u64(u64::MAX)),
+            "ffffffffffffffff"
+        );
+    }
+}
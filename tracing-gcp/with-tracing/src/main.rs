@@ -1,118 +1,356 @@
-use chrono::{SecondsFormat, Utc};
-use serde::Serialize;
-use serde_json::to_string;
-use tracing::field::{Field, Visit};
-use tracing::span::{Attributes, Id};
-use tracing::{Event, Instrument, Subscriber, info, info_span};
-use tracing_subscriber::filter::LevelFilter;
-use tracing_subscriber::layer::Context;
+use gcp_logging_core::{
+    MonitoredResource, PreInitBuffer, detect_project_id, get_gcp_project_id,
+    parse_traceparent, service_version_from_env,
+};
+use std::collections::HashMap;
+use std::env;
+use std::io::Write;
+use tracing::subscriber::DefaultGuard;
+use tracing::{Instrument, debug, info, info_span};
+use tracing_appender::non_blocking::{ErrorCounter, WorkerGuard};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::fmt::MakeWriter;
 use tracing_subscriber::prelude::*;
-use tracing_subscriber::registry::LookupSpan;
-use tracing_subscriber::{Layer, registry};
+use tracing_subscriber::util::TryInitError;
+use tracing_subscriber::{Registry, registry, reload};
+use with_tracing::{GcpLayer, GcpLayerBuilder, gcp_layer};
 
-struct TraceId(String);
-
-#[derive(Default)]
-struct TraceIdVisitor {
-    trace_id: Option<String>,
+/// The project of the traces: the one of the metadata server on GCP, or else the
+/// `GOOGLE_CLOUD_PROJECT` fallback of [`get_gcp_project_id`]. Detect it once and hand
+/// it to both [`buffer_early_logs`] and [`init_logging`].
+async fn gcp_project_id() -> String {
+    detect_project_id().await.unwrap_or_else(get_gcp_project_id)
 }
 
-impl Visit for TraceIdVisitor {
-    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
-        if field.name() == "trace_id" {
-            self.trace_id = Some(format!("{value:?}"))
-        }
-    }
+/// Entries are written by a background thread; keep the returned guard alive until
+/// shutdown so that buffered entries get flushed. Setting `LOG_PRETTY` indents entries
+/// for local reading.
+///
+/// Panics if a global subscriber is already installed.
+fn init_logging(gcp_project_id: String) -> (LoggingGuard, FilterHandle) {
+    try_init_logging(gcp_project_id).expect("logging is already initialized")
 }
 
-#[derive(Default)]
-struct EventVisitor {
-    message: Option<String>,
+/// Like [`init_logging`], but fails instead of panicking when a global subscriber is
+/// already installed.
+fn try_init_logging(
+    gcp_project_id: String,
+) -> Result<(LoggingGuard, FilterHandle), TryInitError> {
+    let (writer, worker) = tracing_appender::non_blocking(std::io::stderr());
+    let guard = LoggingGuard { worker, dropped: writer.error_counter() };
+    let (layer, filter_handle) =
+        gcp_layer(layer_builder(gcp_project_id, writer)).expect("the project id is set");
+    registry().with(layer).try_init()?;
+    Ok((guard, filter_handle))
 }
 
-impl Visit for EventVisitor {
-    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
-        if field.name() == "message" {
-            self.message = Some(format!("{value:?}"))
-        }
-    }
+/// How [`init_logging`] configures the `GcpLayer` writing to `writer`.
+fn layer_builder<W>(gcp_project_id: String, writer: W) -> GcpLayerBuilder<W>
+where
+    W: for<'writer> MakeWriter<'writer> + 'static,
+{
+    GcpLayer::builder()
+        .project_id(gcp_project_id)
+        .with_source_location(true)
+        .resource(MonitoredResource::detect_cloud_run())
+        .version(service_version_from_env().or(Some(env!("CARGO_PKG_VERSION").into())))
+        .writer(writer)
+        .pretty(env::var_os("LOG_PRETTY").is_some())
+        .default_labels(HashMap::from([("service".into(), "with-tracing".into())]))
 }
 
-struct GcpLayer {
-    gcp_project_id: String,
+/// Keeps the last `capacity` entries logged on this thread, e.g. while loading the
+/// configuration, until [`EarlyLogs::replay`] writes them out once logging is
+/// initialized. Entries are formatted as [`init_logging`] formats them in
+/// `gcp_project_id`.
+///
+/// Only this thread logs to the buffer: `#[tokio::main]` runs `main` on it, but
+/// spawned tasks may run elsewhere.
+fn buffer_early_logs(gcp_project_id: String, capacity: usize) -> EarlyLogs {
+    let buffer = PreInitBuffer::new(capacity);
+    let writer = {
+        let buffer = buffer.clone();
+        move || buffer.clone()
+    };
+    // Unlike `SubscriberInitExt::set_default`, this doesn't install the `log` bridge,
+    // which `init_logging` installs for good.
+    let builder = layer_builder(gcp_project_id, writer);
+    let layer = gcp_layer(builder).expect("the project id is set").0;
+    let guard = tracing::subscriber::set_default(registry().with(layer));
+    EarlyLogs { buffer, guard }
 }
 
-#[derive(Serialize)]
-struct LogEntry<'a> {
-    severity: &'a    str,
-    message: String,
-    time: String,
-    #[serde(rename = "logging.googleapis.com/trace")]
-    #[serde(skip_serializing_if = "Option::is_none")]
-    trace: Option<String>,
+/// The entries buffered by [`buffer_early_logs`].
+struct EarlyLogs {
+    buffer: PreInitBuffer,
+    guard: DefaultGuard,
 }
 
-impl<S> Layer<S> for GcpLayer
-where
-    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
-{
-    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
-        if let Some(span) = ctx.span(id) {
-            let mut visitor = TraceIdVisitor::default();
-            attrs.record(&mut visitor);
-            if let Some(trace_id) = visitor.trace_id {
-                span.extensions_mut().insert(TraceId(trace_id));
-            }
-        };
-    }
-
-    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
-        let mut trace = None;
-        if let Some(scope) = ctx.event_scope(event) {
-            for span in scope.from_root() {
-                let extensions = span.extensions();
-                let Some(trace_id) = extensions.get::<TraceId>() else { continue };
-                let t = &trace_id.0;
-                trace = Some(format!("projects/{}/traces/{t}", self.gcp_project_id));
-            }
+impl EarlyLogs {
+    /// Stops buffering, and writes the buffered entries to `writer`, e.g. stderr where
+    /// [`init_logging`] writes. Call it once logging is initialized.
+    fn replay(self, writer: impl Write) {
+        drop(self.guard);
+        if let Err(e) = self.buffer.replay(writer) {
+            eprintln!("failed to write the early log entries: {e}");
         }
-        let mut visitor = EventVisitor::default();
-        event.record(&mut visitor);
-        let entry = LogEntry {
-            severity: event.metadata().level().as_str(),
-            message: visitor.message.unwrap_or_default(),
-            time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
-            trace,
-        };
-        eprintln!("{}", to_string(&entry).unwrap());
     }
 }
 
-fn get_gcp_project_id() -> String {
-    "PROJECT_ID_123".into()
+/// Writes the entries still buffered by [`init_logging`] when dropped.
+struct LoggingGuard {
+    worker: WorkerGuard,
+    dropped: ErrorCounter,
 }
 
-async fn init_logging() {
-    let layer = GcpLayer { gcp_project_id: get_gcp_project_id() };
-    registry().with(layer.with_filter(LevelFilter::INFO)).init();
+impl LoggingGuard {
+    /// Waits for the buffered entries to be written, and returns whether none were
+    /// dropped. Call it before exiting: serverless runtimes may freeze the instance
+    /// before a background writer catches up.
+    fn shutdown(self) -> bool {
+        drop(self.worker);
+        self.dropped.dropped_lines() == 0
+    }
+}
+
+/// Changes the filter set up by [`init_logging`] at runtime.
+type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Logs events at `level` and above from now on, replacing the `RUST_LOG` directives.
+fn set_log_level(handle: &FilterHandle, level: LevelFilter) -> Result<(), reload::Error> {
+    handle.reload(EnvFilter::default().add_directive(level.into()))
 }
 
 async fn do_something() {
-    info!("Doing something");
+    info!(count = 3, "Doing something");
     // ...
     info!("Done doing something");
 }
 
 #[tokio::main]
 async fn main() {
-    init_logging().await;
+    let gcp_project_id = gcp_project_id().await;
+    let early_logs = buffer_early_logs(gcp_project_id.clone(), 100);
+    info!("Loading the configuration");
+    let (guard, filter_handle) = init_logging(gcp_project_id);
+    early_logs.replay(std::io::stderr());
+
+    println!("With trace_id=0...0456 and span_id=000000000000004a");
+    let span = info_span!(
+        "trace_id",
+        trace_id = %"00000000000000000000000000000456",
+        span_id = %"000000000000004a"
+    );
+    do_something().instrument(span).await;
 
-    println!("With trace_id=456");
-    do_something().instrument(info_span!("trace_id", trace_id = %"456")).await;
+    let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+    println!("With traceparent={traceparent}");
+    if let Some((trace_id, span_id, sampled)) = parse_traceparent(traceparent) {
+        let span = info_span!(
+            "trace_id",
+            trace_id = %trace_id,
+            span_id = %span_id,
+            trace_sampled = sampled
+        );
+        do_something().instrument(span).await;
+    }
 
     println!("Without a trace_id:");
     do_something().await;
 
-    println!("With trace_id=789");
-    do_something().instrument(info_span!("trace_id", trace_id = %"789")).await;
+    println!("With trace_id=0...0789 (not sampled)");
+    let span = info_span!(
+        "trace_id",
+        trace_id = %"00000000000000000000000000000789",
+        trace_sampled = false
+    );
+    do_something().instrument(span).await;
+
+    println!("With the level raised to DEBUG");
+    set_log_level(&filter_handle, LevelFilter::DEBUG).unwrap();
+    debug!("Now logging at DEBUG");
+
+    if !guard.shutdown() {
+        eprintln!("Some log entries were dropped");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gcp_logging_core::{CaptureWriter, running_on_gcp};
+    use serde_json::Value;
+    use std::sync::Mutex;
+    use tracing::warn;
+
+    /// Serializes the tests that set environment variables.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// Sets `vars`, after removing the variables the examples read.
+    fn set_env(vars: &[(&str, &str)]) {
+        // SAFETY: the tests that read or write the environment hold `ENV`.
+        unsafe {
+            for var in [
+                "RUST_LOG",
+                "LOG_LEVEL",
+                "LOG_PRETTY",
+                "K_SERVICE",
+                "K_REVISION",
+                "K_CONFIGURATION",
+                "GOOGLE_CLOUD_PROJECT",
+            ] {
+                env::remove_var(var);
+            }
+            for (var, value) in vars {
+                env::set_var(var, value);
+            }
+        }
+    }
+
+    /// The entries `log` writes through [`layer_builder`] with `vars` set.
+    fn entries(vars: &[(&str, &str)], log: impl FnOnce()) -> Vec<Value> {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        set_env(vars);
+        let capture = CaptureWriter::new();
+        let writer = capture.clone();
+        let (layer, _) =
+            gcp_layer(layer_builder("test-project".into(), move || writer.clone()))
+                .unwrap();
+        tracing::subscriber::with_default(registry().with(layer), log);
+        capture.take()
+    }
+
+    fn messages(entries: &[Value]) -> Vec<&str> {
+        entries.iter().map(|e| e["message"].as_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn reads_the_level_from_the_environment() {
+        let log = || {
+            debug!("debug");
+            info!("info");
+        };
+        assert_eq!(messages(&entries(&[], log)), ["info"]);
+        assert_eq!(messages(&entries(&[("LOG_LEVEL", "debug")], log)), ["debug", "info"]);
+        assert_eq!(messages(&entries(&[("RUST_LOG", "debug")], log)), ["debug", "info"]);
+        let vars = [("RUST_LOG", "warn"), ("LOG_LEVEL", "debug")];
+        assert!(entries(&vars, log).is_empty(), "RUST_LOG wins");
+        assert_eq!(messages(&entries(&[("RUST_LOG", "=[")], log)), ["info"]);
+    }
+
+    #[test]
+    fn writes_the_buffered_entries_on_shutdown() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        set_env(&[]);
+        let capture = CaptureWriter::new();
+        let (writer, worker) = tracing_appender::non_blocking(capture.clone());
+        let guard = LoggingGuard { worker, dropped: writer.error_counter() };
+        let (layer, _) = gcp_layer(layer_builder("test-project".into(), writer)).unwrap();
+        tracing::subscriber::with_default(registry().with(layer), || {
+            for i in 0..1000 {
+                info!(i, "buffered");
+            }
+        });
+        assert!(guard.shutdown(), "no entry was dropped");
+        assert_eq!(capture.entries().len(), 1000);
+    }
+
+    #[test]
+    fn writes_the_cloud_run_resource() {
+        let log = || info!("on Cloud Run");
+        let vars = [
+            ("K_SERVICE", "api"),
+            ("K_REVISION", "api-00001-abc"),
+            ("K_CONFIGURATION", "api"),
+        ];
+        assert_eq!(
+            entries(&vars, log)[0]["resource"],
+            serde_json::json!({
+                "type": "cloud_run_revision",
+                "labels": {
+                    "configuration_name": "api",
+                    "revision_name": "api-00001-abc",
+                    "service_name": "api",
+                },
+            })
+        );
+        assert!(entries(&[], log)[0].get("resource").is_none());
+    }
+
+    #[test]
+    fn fails_to_initialize_logging_twice() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        set_env(&[]);
+        let (guard, _) = try_init_logging("test-project".into()).unwrap();
+        assert!(try_init_logging("test-project".into()).is_err());
+        guard.shutdown();
+    }
+
+    #[test]
+    fn raises_the_level_at_runtime() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        set_env(&[]);
+        let capture = CaptureWriter::new();
+        let writer = capture.clone();
+        let (layer, filter_handle) =
+            gcp_layer(layer_builder("test-project".into(), move || writer.clone()))
+                .unwrap();
+        tracing::subscriber::with_default(registry().with(layer), || {
+            debug!("dropped");
+            set_log_level(&filter_handle, LevelFilter::DEBUG).unwrap();
+            debug!("kept");
+        });
+        assert_eq!(messages(&capture.take()), ["kept"]);
+    }
+
+    #[test]
+    fn detects_gcp_from_the_environment() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let on_gcp = |vars| {
+            set_env(vars);
+            running_on_gcp()
+        };
+        assert!(!on_gcp(&[]));
+        assert!(on_gcp(&[("K_SERVICE", "checkout")]));
+        assert!(on_gcp(&[("GOOGLE_CLOUD_PROJECT", "my-project")]));
+        assert!(!on_gcp(&[("K_SERVICE", "")]), "empty vars don't count");
+    }
+
+    #[test]
+    fn honors_per_target_directives() {
+        let log = || {
+            debug!(target: "sqlx", "sqlx debug");
+            info!(target: "sqlx", "sqlx info");
+            warn!(target: "sqlx", "sqlx warn");
+            debug!(target: "my_app", "my_app debug");
+            debug!(target: "other", "other debug");
+            info!(target: "other", "other info");
+        };
+        let vars = [("RUST_LOG", "info,sqlx=warn,my_app=debug")];
+        assert_eq!(
+            messages(&entries(&vars, log)),
+            ["sqlx warn", "my_app debug", "other info"]
+        );
+    }
+
+    #[test]
+    fn replays_the_early_entries_in_the_detected_project() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        set_env(&[]);
+        let early_logs = buffer_early_logs("detected-project".into(), 10);
+        info_span!("config", trace_id = "4bf92f3577b34da6a3ce929d0e0e4736")
+            .in_scope(|| info!("Loading the configuration"));
+        let mut replayed = Vec::new();
+        early_logs.replay(&mut replayed);
+        let entries: Vec<Value> = String::from_utf8(replayed)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(messages(&entries), ["Loading the configuration"]);
+        assert_eq!(
+            entries[0]["logging.googleapis.com/trace"],
+            "projects/detected-project/traces/4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+    }
 }
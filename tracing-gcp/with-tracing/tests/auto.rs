@@ -0,0 +1,63 @@
+//! The layer `auto_layer` picks on GCP and elsewhere.
+
+use serde_json::Value;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use tracing::info;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
+use with_tracing::{GcpLayer, auto_layer};
+
+/// Appends to a shared buffer.
+struct SharedWriter(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// What an event logs through `auto_layer` with `vars` set, and the other variables
+/// `running_on_gcp` reads unset.
+fn output(vars: &[(&str, &str)]) -> String {
+    // SAFETY: this is the only test of this binary, so nothing else reads the
+    // environment concurrently.
+    unsafe {
+        for var in ["K_SERVICE", "GOOGLE_CLOUD_PROJECT", "RUST_LOG", "LOG_LEVEL"] {
+            std::env::remove_var(var);
+        }
+        for (var, value) in vars {
+            std::env::set_var(var, value);
+        }
+    }
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let writer = output.clone();
+    let builder = GcpLayer::builder()
+        .project_id("test-project")
+        .writer(BoxMakeWriter::new(move || SharedWriter(writer.clone())));
+    let (layer, _) = auto_layer(builder).unwrap();
+    tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+        info!("picked")
+    });
+    String::from_utf8(output.lock().unwrap().clone()).unwrap()
+}
+
+#[test]
+fn writes_gcp_json_on_gcp_and_fmt_output_elsewhere() {
+    for vars in [[("K_SERVICE", "checkout")], [("GOOGLE_CLOUD_PROJECT", "test-project")]]
+    {
+        let output = output(&vars);
+        let entry: Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(entry["message"], "picked");
+        assert_eq!(entry["severity"], "INFO");
+    }
+
+    let output = output(&[]);
+    assert!(serde_json::from_str::<Value>(&output).is_err(), "{output}");
+    assert!(output.contains("picked"), "{output}");
+    assert!(output.contains("INFO"), "{output}");
+}
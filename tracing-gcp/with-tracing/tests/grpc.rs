@@ -0,0 +1,101 @@
+//! Calls to a `tonic` service wrapped in the `TraceContextLayer` of `gcp-logging-core`.
+
+use gcp_logging_core::CaptureWriter;
+use gcp_logging_core::middleware::TraceContextLayer;
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tonic::body::Body;
+use tonic::server::{Grpc, NamedService, UnaryService};
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::{Endpoint, Server};
+use tonic_prost::ProstCodec;
+use tower::{Layer, Service};
+use tracing::info;
+use tracing_subscriber::prelude::*;
+use with_tracing::GcpLayer;
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// A service with a single unary `Greet` method, as `tonic`'s code generator would
+/// make it, taking and returning empty messages.
+#[derive(Clone)]
+struct Greeter;
+
+impl NamedService for Greeter {
+    const NAME: &'static str = "test.Greeter";
+}
+
+impl Service<http::Request<Body>> for Greeter {
+    type Response = http::Response<Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<Result<http::Response<Body>, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+        Box::pin(async move {
+            let mut grpc = Grpc::new(ProstCodec::<(), ()>::default());
+            Ok(grpc.unary(Greet, req).await)
+        })
+    }
+}
+
+/// The handler of `Greet`, logging from its future.
+struct Greet;
+
+impl UnaryService<()> for Greet {
+    type Response = ();
+    type Future = BoxFuture<Result<tonic::Response<()>, tonic::Status>>;
+
+    fn call(&mut self, _request: tonic::Request<()>) -> Self::Future {
+        Box::pin(async {
+            info!("greeted");
+            Ok(tonic::Response::new(()))
+        })
+    }
+}
+
+#[tokio::test]
+async fn runs_calls_in_the_trace_of_their_metadata() {
+    let capture = CaptureWriter::new();
+    let writer = capture.clone();
+    let layer = GcpLayer::builder()
+        .project_id("test-project")
+        .writer(move || writer.clone())
+        .build()
+        .unwrap();
+    // The test runtime runs the server on this thread, where the subscriber is set.
+    let _guard =
+        tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+
+    let incoming = TcpIncoming::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr().unwrap();
+    let service = TraceContextLayer::default().layer(Greeter);
+    tokio::spawn(Server::builder().add_service(service).serve_with_incoming(incoming));
+
+    let channel =
+        Endpoint::from_shared(format!("http://{addr}")).unwrap().connect().await.unwrap();
+    let mut client = tonic::client::Grpc::new(channel);
+    client.ready().await.unwrap();
+    let mut request = tonic::Request::new(());
+    request.metadata_mut().insert(
+        "traceparent",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".parse().unwrap(),
+    );
+    let path = "/test.Greeter/Greet".parse().unwrap();
+    let codec = ProstCodec::<(), ()>::default();
+    client.unary(request, path, codec).await.unwrap();
+
+    // `tonic` and `hyper` log too.
+    let entries = capture.take();
+    let entry = entries.iter().find(|entry| entry["message"] == "greeted").unwrap();
+    assert_eq!(
+        entry["logging.googleapis.com/trace"],
+        "projects/test-project/traces/4bf92f3577b34da6a3ce929d0e0e4736"
+    );
+    assert_eq!(entry["logging.googleapis.com/spanId"], "00f067aa0ba902b7");
+    assert_eq!(entry["logging.googleapis.com/trace_sampled"], true);
+}
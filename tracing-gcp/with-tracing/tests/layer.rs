@@ -0,0 +1,1258 @@
+//! The entries `GcpLayer` writes, captured with a `CaptureWriter`.
+
+use chrono::{DateTime, Utc};
+use gcp_logging_core::{
+    CaptureWriter, EntryKeys, FieldBudget, FieldFilter, FixedClock, FnClock, HttpRequest,
+    LockedWriter, OversizedEntry, PayloadFormat, REPORTED_ERROR_EVENT_TYPE,
+    ServiceContext, TargetPlacement, TimestampFormat,
+};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use tracing::{Level, error, info, info_span, warn};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
+use with_tracing::{
+    GcpLayer, GcpLayerBuilder, SpanNameLabels, current_trace_id, gcp_layer,
+    log_request_summary, record_http_request,
+};
+
+const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+fn now() -> DateTime<Utc> {
+    "2024-05-01T12:00:00.123456789Z".parse().unwrap()
+}
+
+/// A layer of the `test-project` project configured by `configure`, writing to the
+/// returned `CaptureWriter` at the time of [`now`].
+fn layer(
+    configure: impl FnOnce(GcpLayerBuilder<BoxMakeWriter>) -> GcpLayerBuilder<BoxMakeWriter>,
+) -> (GcpLayer<BoxMakeWriter>, CaptureWriter) {
+    let capture = CaptureWriter::new();
+    let writer = capture.clone();
+    let builder = GcpLayer::builder()
+        .project_id("test-project")
+        .clock(FixedClock(now()))
+        .writer(BoxMakeWriter::new(move || writer.clone()));
+    (configure(builder).build().unwrap(), capture)
+}
+
+/// The entries written while `log` runs with a layer configured by `configure`.
+fn capture(
+    configure: impl FnOnce(GcpLayerBuilder<BoxMakeWriter>) -> GcpLayerBuilder<BoxMakeWriter>,
+    log: impl FnOnce(),
+) -> Vec<Value> {
+    let (layer, capture) = layer(configure);
+    tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), log);
+    capture.take()
+}
+
+/// The bytes written while `log` runs with a layer configured by `configure`.
+fn output(
+    configure: impl FnOnce(GcpLayerBuilder<BoxMakeWriter>) -> GcpLayerBuilder<BoxMakeWriter>,
+    log: impl FnOnce(),
+) -> String {
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let writer = output.clone();
+    let (layer, _) = layer(|b| {
+        configure(b).writer(BoxMakeWriter::new(move || SharedWriter(writer.clone())))
+    });
+    tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), log);
+    String::from_utf8(output.lock().unwrap().clone()).unwrap()
+}
+
+/// Appends to a shared buffer.
+struct SharedWriter(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The entries written while `log` runs with a default layer.
+fn entries(log: impl FnOnce()) -> Vec<Value> {
+    capture(|b| b, log)
+}
+
+#[test]
+fn writes_trace_sampled_of_traced_spans() {
+    let entries = entries(|| {
+        info_span!("request", trace_id = TRACE_ID, trace_sampled = true)
+            .in_scope(|| info!("handled"));
+    });
+    assert_eq!(entries[0]["logging.googleapis.com/trace_sampled"], json!(true));
+    assert_eq!(
+        entries[0]["logging.googleapis.com/trace"],
+        format!("projects/test-project/traces/{TRACE_ID}")
+    );
+}
+
+#[test]
+fn writes_the_source_line_of_events() {
+    let mut line = 0;
+    let entries = capture(
+        |b| b.with_source_location(true),
+        || {
+            line = line!() + 1;
+            info!("here");
+        },
+    );
+    let source_location = &entries[0]["logging.googleapis.com/sourceLocation"];
+    assert_eq!(source_location["line"], line);
+    assert_eq!(source_location["file"], file!());
+}
+
+#[test]
+fn writes_event_fields_as_payload_members() {
+    let entries = entries(|| info!(count = 3, "counted"));
+    assert_eq!(entries[0]["count"], json!(3));
+    assert_eq!(entries[0]["message"], "counted");
+}
+
+#[test]
+fn inherits_the_fields_of_enclosing_spans() {
+    let entries = entries(|| {
+        info_span!("request", user = "alice", table = "orders").in_scope(|| {
+            info_span!("db", table = "users").in_scope(|| info!(rows = 2, "queried"));
+        });
+    });
+    assert_eq!(entries[0]["user"], "alice");
+    assert_eq!(entries[0]["table"], "users", "the nearest span wins");
+    assert_eq!(entries[0]["rows"], 2);
+}
+
+#[test]
+fn uses_the_outermost_trace_id() {
+    let inner = "00000000000000000000000000000789";
+    let entries = entries(|| {
+        info_span!("request", trace_id = TRACE_ID).in_scope(|| {
+            info_span!("operation", trace_id = inner).in_scope(|| info!("nested"));
+        });
+    });
+    assert_eq!(
+        entries[0]["logging.googleapis.com/trace"],
+        format!("projects/test-project/traces/{TRACE_ID}")
+    );
+}
+
+#[test]
+fn merges_per_event_labels_over_the_default_ones() {
+    let default_labels = HashMap::from([
+        ("service".to_owned(), "api".to_owned()),
+        ("region".to_owned(), "europe-west1".to_owned()),
+    ]);
+    let entries = capture(
+        |b| b.default_labels(default_labels),
+        || {
+            info_span!("request", label.tenant = "acme")
+                .in_scope(|| info!(label.region = "us-east1", "labelled"));
+        },
+    );
+    let labels = &entries[0]["logging.googleapis.com/labels"];
+    assert_eq!(labels["service"], "api");
+    assert_eq!(labels["tenant"], "acme");
+    assert_eq!(labels["region"], "us-east1");
+    assert!(entries[0].get("label.region").is_none());
+}
+
+#[test]
+fn writes_the_time_in_either_format() {
+    let entries = entries(|| info!("rfc3339"));
+    assert_eq!(entries[0]["time"], "2024-05-01T12:00:00.123Z");
+    assert!(entries[0].get("timestamp").is_none());
+
+    let entries = capture(
+        |b| b.timestamp_format(TimestampFormat::SecondsNanos),
+        || info!("seconds and nanos"),
+    );
+    assert_eq!(
+        entries[0]["timestamp"],
+        json!({ "seconds": now().timestamp(), "nanos": 123456789 })
+    );
+    assert!(entries[0].get("time").is_none());
+}
+
+#[test]
+fn stamps_entries_with_the_injected_clock() {
+    let time = "2001-02-03T04:05:06.789Z".parse().unwrap();
+    let entries = capture(|b| b.clock(FixedClock(time)), || info!("frozen"));
+    assert_eq!(entries[0]["time"], "2001-02-03T04:05:06.789Z");
+}
+
+#[test]
+fn gives_rapid_events_distinct_insert_ids() {
+    let entries = capture(
+        |b| b.with_insert_id(true),
+        || {
+            info!("first");
+            info!("second");
+        },
+    );
+    let ids: Vec<_> =
+        entries.iter().map(|e| e["logging.googleapis.com/insertId"].clone()).collect();
+    assert!(ids.iter().all(Value::is_string), "{ids:?}");
+    assert_ne!(ids[0], ids[1]);
+}
+
+#[test]
+fn writes_the_http_request_of_the_span() {
+    let http_request = HttpRequest {
+        request_method: Some("GET".into()),
+        request_url: Some("/orders".into()),
+        status: Some(200),
+        user_agent: Some("curl/8.0".into()),
+        ..HttpRequest::default()
+    };
+    let entries = entries(|| {
+        let span = info_span!("request");
+        record_http_request(&span, http_request);
+        span.in_scope(|| info!("served"));
+    });
+    assert_eq!(
+        entries[0]["httpRequest"],
+        json!({
+            "requestMethod": "GET",
+            "requestUrl": "/orders",
+            "status": 200,
+            "userAgent": "curl/8.0",
+        })
+    );
+}
+
+#[test]
+fn writes_string_trace_ids_without_quotes() {
+    let trace = format!("projects/test-project/traces/{TRACE_ID}");
+    let entries = entries(|| {
+        info_span!("str", trace_id = TRACE_ID).in_scope(|| info!("recorded as str"));
+        info_span!("display", trace_id = %TRACE_ID).in_scope(|| info!("as Display"));
+    });
+    assert_eq!(entries[0]["logging.googleapis.com/trace"], trace);
+    assert_eq!(entries[1]["logging.googleapis.com/trace"], trace);
+}
+
+#[test]
+fn overrides_the_severity_with_a_valid_severity_field() {
+    let entries = entries(|| {
+        info!(severity = "CRITICAL", "boom");
+        info!(severity = "LOUD", "not a severity");
+    });
+    assert_eq!(entries[0]["severity"], "CRITICAL");
+    assert_eq!(entries[1]["severity"], "INFO");
+}
+
+#[test]
+fn marks_only_error_events_as_reported_errors() {
+    let service_context =
+        ServiceContext { service: "api".into(), version: Some("1.2.3".into()) };
+    let entries = capture(
+        |b| b.error_reporting(service_context),
+        || {
+            error!("failed");
+            info!("fine");
+        },
+    );
+    assert_eq!(entries[0]["@type"], REPORTED_ERROR_EVENT_TYPE);
+    assert_eq!(
+        entries[0]["serviceContext"],
+        json!({ "service": "api", "version": "1.2.3" })
+    );
+    assert!(entries[1].get("@type").is_none());
+    assert!(entries[1].get("serviceContext").is_none());
+}
+
+#[test]
+fn writes_the_trace_of_the_core_with_trace_id() {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let entries = entries(|| {
+        runtime.block_on(gcp_logging_core::with_trace_id(TRACE_ID, async {
+            info!("in the trace");
+        }));
+    });
+    assert_eq!(
+        entries[0]["logging.googleapis.com/trace"],
+        format!("projects/test-project/traces/{TRACE_ID}")
+    );
+}
+
+#[test]
+fn places_the_target_as_configured() {
+    let log = || info!(target: "billing", "charged");
+    let entries = capture(|b| b.target_placement(TargetPlacement::Label), log);
+    assert_eq!(entries[0]["logging.googleapis.com/labels"]["target"], "billing");
+    assert!(entries[0].get("target").is_none());
+
+    let entries = capture(|b| b.target_placement(TargetPlacement::Field), log);
+    assert_eq!(entries[0]["target"], "billing");
+
+    let entries = capture(
+        |b| b.target_placement(TargetPlacement::Label),
+        || {
+            info!(target: "", "no target");
+        },
+    );
+    assert!(entries[0]["logging.googleapis.com/labels"].get("target").is_none());
+}
+
+#[test]
+fn merges_json_payload_objects_into_the_entry() {
+    let entries = entries(|| {
+        info!(json_payload = r#"{"a":1}"#, "replaced");
+        info!(json_payload = "[1]", "kept");
+    });
+    assert_eq!(entries[0]["a"], 1);
+    assert!(entries[0].get("message").is_none());
+    assert!(entries[0].get("json_payload").is_none());
+    assert_eq!(entries[1]["json_payload"], "[1]");
+    assert_eq!(entries[1]["message"], "kept");
+}
+
+#[test]
+fn marks_the_first_and_last_entries_of_operations() {
+    let entries = entries(|| {
+        info_span!("job", operation_id = "op-1", operation_producer = "worker").in_scope(
+            || {
+                info!("started");
+                info!("progressed");
+            },
+        );
+    });
+    let operations: Vec<_> =
+        entries.iter().map(|e| &e["logging.googleapis.com/operation"]).collect();
+    assert_eq!(
+        operations,
+        [
+            &json!({ "id": "op-1", "producer": "worker", "first": true }),
+            &json!({ "id": "op-1", "producer": "worker" }),
+            &json!({ "id": "op-1", "producer": "worker", "last": true }),
+        ]
+    );
+}
+
+#[test]
+fn writes_the_elapsed_time_of_timed_spans() {
+    let entries = capture(
+        |b| b.with_span_timing(true),
+        || {
+            info_span!("slow").in_scope(|| {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            });
+        },
+    );
+    assert_eq!(entries[0]["span"], "slow");
+    let elapsed_ms = entries[0]["elapsed_ms"].as_f64().unwrap();
+    assert!((50.0..1000.0).contains(&elapsed_ms), "{elapsed_ms}");
+}
+
+#[test]
+fn formats_the_trace_in_the_trace_project() {
+    let entries = entries(|| {
+        info_span!("request", trace_id = TRACE_ID, trace_project = "tracing-project")
+            .in_scope(|| info!("elsewhere"));
+        info_span!("request", trace_id = TRACE_ID).in_scope(|| info!("here"));
+    });
+    assert_eq!(
+        entries[0]["logging.googleapis.com/trace"],
+        format!("projects/tracing-project/traces/{TRACE_ID}")
+    );
+    assert_eq!(
+        entries[1]["logging.googleapis.com/trace"],
+        format!("projects/test-project/traces/{TRACE_ID}")
+    );
+}
+
+#[test]
+fn indents_entries_only_when_pretty() {
+    let compact = output(|b| b, || info!(count = 3, "compact"));
+    assert_eq!(compact.lines().count(), 1, "{compact}");
+    assert!(compact.ends_with("}\n"));
+
+    let pretty = output(|b| b.pretty(true), || info!(count = 3, "pretty"));
+    assert!(pretty.lines().count() > 1, "{pretty}");
+    assert!(pretty.contains("\n  \"count\": 3"), "{pretty}");
+    assert!(pretty.ends_with("}\n"));
+    let entry: Value = serde_json::from_str(&pretty).unwrap();
+    assert_eq!(entry["message"], "pretty");
+}
+
+/// The setup the helpers above share, spelled out: a `CaptureWriter` to read the
+/// entries back and a `FixedClock` to make their `time` deterministic.
+#[test]
+fn captures_entries_at_a_fixed_time() {
+    let capture = CaptureWriter::new();
+    let writer = capture.clone();
+    let layer = GcpLayer::builder()
+        .project_id("test-project")
+        .clock(FixedClock("2024-05-01T12:00:00Z".parse().unwrap()))
+        .writer(move || writer.clone())
+        .build()
+        .unwrap();
+    tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+        info_span!("request", trace_id = TRACE_ID).in_scope(|| error!("failed"));
+    });
+    assert_eq!(
+        capture.take(),
+        [json!({
+            "severity": "ERROR",
+            "message": "failed",
+            "time": "2024-05-01T12:00:00.000Z",
+            "logging.googleapis.com/trace":
+                format!("projects/test-project/traces/{TRACE_ID}"),
+            "logging.googleapis.com/trace_sampled": true,
+            "logging.googleapis.com/spanId": "0000000000000001",
+        })]
+    );
+}
+
+#[test]
+fn writes_the_message_under_the_configured_key() {
+    let entries = capture(|b| b.message_key("msg"), || info!("renamed"));
+    assert_eq!(entries[0]["msg"], "renamed");
+    assert!(entries[0].get("message").is_none());
+}
+
+#[test]
+fn writes_the_thread_labels() {
+    let entries = std::thread::Builder::new()
+        .name("worker".into())
+        .spawn(|| capture(|b| b.with_thread_labels(true), || info!("threaded")))
+        .unwrap()
+        .join()
+        .unwrap();
+    let labels = &entries[0]["logging.googleapis.com/labels"];
+    assert_eq!(labels["thread.name"], "worker");
+    assert!(labels["thread.id"].as_str().unwrap().parse::<u64>().is_ok());
+    assert_eq!(labels["process.pid"], std::process::id().to_string());
+}
+
+#[test]
+fn writes_the_module_path_as_the_function() {
+    let entries = capture(|b| b.with_source_location(true), || info!("here"));
+    assert_eq!(entries[0]["logging.googleapis.com/sourceLocation"]["function"], "layer");
+}
+
+#[test]
+fn writes_plain_messages_as_text() {
+    let output = output(
+        |b| b.payload_format(PayloadFormat::TextIfUnstructured),
+        || info!("just a message"),
+    );
+    assert_eq!(output, "just a message\n");
+}
+
+#[test]
+fn writes_structured_entries_as_json() {
+    let output = output(
+        |b| b.payload_format(PayloadFormat::TextIfUnstructured),
+        || {
+            info!(count = 3, "with a field");
+            info_span!("request", trace_id = TRACE_ID).in_scope(|| info!("traced"));
+            info_span!("request", label.tenant = "acme").in_scope(|| info!("labelled"));
+            error!("an error");
+        },
+    );
+    let entries: Vec<Value> =
+        output.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    let messages: Vec<_> = entries.iter().map(|e| e["message"].clone()).collect();
+    assert_eq!(messages, ["with a field", "traced", "labelled", "an error"]);
+    assert_eq!(entries[3]["severity"], "ERROR");
+}
+
+#[tokio::test]
+async fn reads_the_builder_settings_from_a_config_file() {
+    let path =
+        std::env::temp_dir().join(format!("gcp-layer-{}.json", std::process::id()));
+    let config = json!({
+        "project_id": "config-project",
+        "default_labels": { "team": "payments" },
+        "service": "api",
+        "version": "1.2.3",
+    });
+    std::fs::write(&path, config.to_string()).unwrap();
+    let builder = GcpLayerBuilder::from_config_path(&path).await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let capture = CaptureWriter::new();
+    let writer = capture.clone();
+    let layer = builder.writer(move || writer.clone()).build().unwrap();
+    tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+        info_span!("request", trace_id = TRACE_ID).in_scope(|| error!("failed"));
+    });
+    let entries = capture.take();
+    assert_eq!(
+        entries[0]["logging.googleapis.com/trace"],
+        format!("projects/config-project/traces/{TRACE_ID}")
+    );
+    assert_eq!(entries[0]["logging.googleapis.com/labels"]["team"], "payments");
+    assert_eq!(
+        entries[0]["serviceContext"],
+        json!({ "service": "api", "version": "1.2.3" })
+    );
+}
+
+#[test]
+fn writes_non_finite_floats_as_strings() {
+    let entries = entries(|| {
+        info!(ratio = f64::NAN, up = f64::INFINITY, down = f64::NEG_INFINITY, half = 0.5);
+    });
+    assert_eq!(entries[0]["ratio"], "NaN");
+    assert_eq!(entries[0]["up"], "Infinity");
+    assert_eq!(entries[0]["down"], "-Infinity");
+    assert_eq!(entries[0]["half"], 0.5);
+}
+
+#[test]
+fn keeps_only_the_errors_of_spans_sampled_out() {
+    let entries = capture(
+        |b| b.sample_spans(0.0, ["request"]),
+        || {
+            info_span!("request").in_scope(|| {
+                info!("dropped");
+                error!("kept");
+            });
+            info!("not sampled");
+        },
+    );
+    let messages: Vec<_> = entries.iter().map(|e| e["message"].clone()).collect();
+    assert_eq!(messages, ["kept", "not sampled"]);
+}
+
+#[test]
+fn writes_the_encoded_log_name() {
+    let routed = capture(|b| b.log_name("my app/requests"), || info!("routed"));
+    assert_eq!(routed[0]["logName"], "projects/test-project/logs/my%20app%2Frequests");
+    assert!(entries(|| info!("default log"))[0].get("logName").is_none());
+}
+
+#[test]
+fn reports_the_events_at_the_threshold_and_above() {
+    let service_context = ServiceContext { service: "api".into(), version: None };
+    let entries = capture(
+        |b| b.error_reporting(service_context).report_threshold(Level::WARN),
+        || {
+            warn!("degraded");
+            info!("fine");
+        },
+    );
+    assert_eq!(entries[0]["@type"], REPORTED_ERROR_EVENT_TYPE);
+    assert_eq!(entries[0]["serviceContext"]["service"], "api");
+    assert!(entries[1].get("@type").is_none());
+}
+
+#[test]
+fn redacts_and_drops_the_filtered_fields() {
+    let field_filter =
+        FieldFilter::default().redact_fields(["password"]).drop_fields(["token"]);
+    let entries = capture(
+        |b| b.field_filter(field_filter),
+        || {
+            info_span!("request", Token = "abc")
+                .in_scope(|| info!(PassWord = "hunter2", user = "alice", "signed in"));
+        },
+    );
+    assert_eq!(entries[0]["PassWord"], "***");
+    assert!(entries[0].get("Token").is_none());
+    assert_eq!(entries[0]["user"], "alice");
+}
+
+/// An error caused by another one.
+#[derive(Debug)]
+struct Outer(std::io::Error);
+
+impl std::fmt::Display for Outer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("failed to load the configuration")
+    }
+}
+
+impl std::error::Error for Outer {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+#[test]
+fn writes_the_source_chain_of_errors() {
+    let error = Outer(std::io::Error::other("config.json not found"));
+    let entries = entries(|| error!(error = &error as &dyn std::error::Error, "failed"));
+    assert_eq!(
+        entries[0]["error"],
+        json!(["failed to load the configuration", "config.json not found"])
+    );
+}
+
+#[test]
+fn splits_the_entries_between_two_writers() {
+    let (errors, others) = (CaptureWriter::new(), CaptureWriter::new());
+    let (error_writer, other_writer) = (errors.clone(), others.clone());
+    let layer = GcpLayer::builder()
+        .project_id("test-project")
+        .split_writer(
+            Level::ERROR,
+            move || error_writer.clone(),
+            move || other_writer.clone(),
+        )
+        .build()
+        .unwrap();
+    tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+        info!("fine");
+        error!("failed");
+    });
+    let messages = |capture: &CaptureWriter| -> Vec<Value> {
+        capture.take().iter().map(|e| e["message"].clone()).collect()
+    };
+    assert_eq!(messages(&errors), ["failed"]);
+    assert_eq!(messages(&others), ["fine"]);
+}
+
+#[test]
+fn evaluates_the_dynamic_labels_for_every_entry() {
+    let tenant = Arc::new(Mutex::new("acme"));
+    let current = tenant.clone();
+    let entries = capture(
+        |b| {
+            b.dynamic_labels(move || {
+                HashMap::from([(
+                    "tenant".to_owned(),
+                    current.lock().unwrap().to_string(),
+                )])
+            })
+        },
+        || {
+            info!("first");
+            *tenant.lock().unwrap() = "globex";
+            info!("second");
+        },
+    );
+    assert_eq!(entries[0]["logging.googleapis.com/labels"]["tenant"], "acme");
+    assert_eq!(entries[1]["logging.googleapis.com/labels"]["tenant"], "globex");
+}
+
+/// A writer whose writes all fail.
+struct FailingWriter;
+
+impl Write for FailingWriter {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::other("disk full"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn hands_write_errors_to_the_error_callback() {
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let (layer, _) = layer(|b| {
+        let errors = errors.clone();
+        b.writer(BoxMakeWriter::new(|| FailingWriter))
+            .on_error(move |error| errors.lock().unwrap().push(error.to_string()))
+    });
+    tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+        info!("lost");
+    });
+    let errors = errors.lock().unwrap();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].contains("disk full"), "{errors:?}");
+}
+
+#[test]
+fn stamps_entries_with_the_event_time_when_the_write_is_delayed() {
+    let time = Arc::new(Mutex::new(now()));
+    let capture = CaptureWriter::new();
+    let (layer, _) = layer(|b| {
+        let (clock_time, writer_time) = (time.clone(), time.clone());
+        let writer = capture.clone();
+        b.clock(FnClock(move || *clock_time.lock().unwrap())).writer(BoxMakeWriter::new(
+            move || {
+                // The write happens a minute after the event.
+                *writer_time.lock().unwrap() += chrono::Duration::minutes(1);
+                writer.clone()
+            },
+        ))
+    });
+    tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+        info!("delayed");
+    });
+    assert_eq!(capture.take()[0]["time"], "2024-05-01T12:00:00.123Z");
+    assert_eq!(*time.lock().unwrap(), now() + chrono::Duration::minutes(1));
+}
+
+#[test]
+fn writes_only_the_severity_message_and_time_by_default() {
+    let capture = CaptureWriter::new();
+    let writer = capture.clone();
+    let layer = GcpLayer::builder()
+        .project_id("test-project")
+        .writer(move || writer.clone())
+        .build()
+        .unwrap();
+    tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+        info!("minimal");
+    });
+    let entries = capture.take();
+    let keys: Vec<_> =
+        entries[0].as_object().unwrap().keys().map(String::as_str).collect();
+    assert_eq!(keys, ["message", "severity", "time"]);
+}
+
+#[test]
+fn records_the_names_of_nested_spans_as_labels() {
+    let log = || {
+        info_span!("request")
+            .in_scope(|| info_span!("db_query").in_scope(|| info!("queried")));
+    };
+    let innermost = capture(|b| b.span_name_labels(SpanNameLabels::Innermost), log);
+    let labels = &innermost[0]["logging.googleapis.com/labels"];
+    assert_eq!(labels["span.name"], "db_query");
+    assert_eq!(labels.get("spans"), None);
+    let path = capture(|b| b.span_name_labels(SpanNameLabels::Path), log);
+    assert_eq!(path[0]["logging.googleapis.com/labels"]["spans"], "request/db_query");
+    assert_eq!(entries(log)[0].get("logging.googleapis.com/labels"), None);
+}
+
+#[test]
+fn truncates_oversized_values_to_the_configured_length() {
+    let body = "x".repeat(1024 * 1024);
+    let entries = capture(
+        |b| b.max_value_len(16),
+        || info!(body = body.as_str(), user = "alice", "request received"),
+    );
+    assert_eq!(entries[0]["body"], format!("{}…", "x".repeat(16)));
+    assert_eq!(entries[0]["user"], "alice");
+    assert_eq!(entries[0]["message"], "request received");
+    assert_eq!(entries[0]["truncated"], true);
+    let short = capture(|b| b.max_value_len(16), || info!(user = "alice", "short"));
+    assert_eq!(short[0].get("truncated"), None);
+}
+
+#[test]
+fn formats_integer_and_string_trace_ids_uniformly() {
+    let trace = |log: fn()| entries(log)[0].get("logging.googleapis.com/trace").cloned();
+    let expected = Some(json!(format!("projects/test-project/traces/{TRACE_ID}")));
+    assert_eq!(
+        trace(|| info_span!("s", trace_id = 0x4bf92f3577b34da6a3ce929d0e0e4736u128)
+            .in_scope(|| info!("u128"))),
+        expected,
+    );
+    assert_eq!(
+        trace(|| info_span!("s", trace_id = TRACE_ID).in_scope(|| info!("str"))),
+        expected
+    );
+    assert_eq!(
+        trace(|| info_span!("s", trace_id = ?TRACE_ID).in_scope(|| info!("debug"))),
+        expected,
+    );
+    assert_eq!(
+        trace(|| info_span!("s", trace_id = 0x456u64).in_scope(|| info!("u64"))),
+        Some(json!("projects/test-project/traces/00000000000000000000000000000456")),
+    );
+    assert_eq!(
+        trace(|| info_span!("s", trace_id = 0x456i64).in_scope(|| info!("i64"))),
+        Some(json!("projects/test-project/traces/00000000000000000000000000000456")),
+    );
+    assert_eq!(
+        trace(|| info_span!("s", trace_id = -1).in_scope(|| info!("negative"))),
+        None
+    );
+    assert_eq!(
+        trace(|| info_span!("s", trace_id = " ").in_scope(|| info!("empty"))),
+        None
+    );
+}
+
+#[test]
+fn keeps_the_correlation_id_label_across_the_events_of_a_scope() {
+    let correlation_ids = |entries: &[Value]| -> Vec<Value> {
+        entries
+            .iter()
+            .map(|e| e["logging.googleapis.com/labels"]["correlation_id"].clone())
+            .collect()
+    };
+    let given = entries(|| {
+        info_span!("request", correlation_id = "req-42").in_scope(|| {
+            info!("received");
+            info_span!("db_query").in_scope(|| info!("queried"));
+            info!("handled");
+        });
+    });
+    assert_eq!(correlation_ids(&given), ["req-42", "req-42", "req-42"]);
+    assert_eq!(given[0].get("logging.googleapis.com/trace"), None);
+
+    let generated = capture(
+        |b| b.with_correlation_ids(true),
+        || {
+            info_span!("request").in_scope(|| {
+                info!("received");
+                info!("handled");
+            });
+            info_span!("request").in_scope(|| info!("other request"));
+        },
+    );
+    let ids = correlation_ids(&generated);
+    assert!(ids[0].is_string(), "{ids:?}");
+    assert_eq!(ids[0], ids[1]);
+    assert_ne!(ids[1], ids[2]);
+}
+
+#[test]
+fn picks_up_a_trace_id_recorded_after_the_span_was_created() {
+    let entries = entries(|| {
+        let span = info_span!("request", trace_id = tracing::field::Empty);
+        span.in_scope(|| info!("before"));
+        span.record("trace_id", TRACE_ID);
+        span.in_scope(|| info!("after"));
+    });
+    assert_eq!(entries[0].get("logging.googleapis.com/trace"), None);
+    assert_eq!(
+        entries[1]["logging.googleapis.com/trace"],
+        format!("projects/test-project/traces/{TRACE_ID}")
+    );
+}
+
+#[test]
+fn replaces_oversized_entries_with_a_warning() {
+    let body = "x".repeat(2048);
+    let output = output(
+        |b| b.entry_size_limit(1024, OversizedEntry::Drop),
+        || {
+            info_span!("request", trace_id = TRACE_ID)
+                .in_scope(|| info!(body = body.as_str(), "request received"));
+        },
+    );
+    let entry: Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(entry["severity"], "WARNING");
+    let message = entry["message"].as_str().unwrap();
+    assert!(
+        message.starts_with("dropped a ") && message.ends_with(" byte limit"),
+        "{message}"
+    );
+    assert_eq!(entry.get("body"), None);
+    assert_eq!(
+        entry["logging.googleapis.com/trace"],
+        format!("projects/test-project/traces/{TRACE_ID}")
+    );
+}
+
+#[test]
+fn truncates_the_largest_field_of_oversized_entries() {
+    // Quotes take two bytes each in the JSON.
+    let body = "\"".repeat(2048);
+    let output = output(
+        |b| b.entry_size_limit(1024, OversizedEntry::TruncateLargestField),
+        || info!(body = body.as_str(), user = "alice", "request received"),
+    );
+    assert!(output.len() <= 1024, "{output}");
+    let entry: Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(entry["severity"], "INFO");
+    assert_eq!(entry["message"], "request received");
+    assert_eq!(entry["user"], "alice");
+    assert_eq!(entry["truncated"], true);
+    let body = entry["body"].as_str().unwrap();
+    assert!(body.ends_with('…') && body.len() > 256, "{} bytes", body.len());
+}
+
+/// Counts the events it sees.
+#[derive(Clone, Default)]
+struct Counting(Arc<Mutex<usize>>);
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Counting {
+    fn on_event(
+        &self,
+        _event: &tracing::Event<'_>,
+        _ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        *self.0.lock().unwrap() += 1;
+    }
+}
+
+#[test]
+fn composes_with_other_layers() {
+    let capture = CaptureWriter::new();
+    let writer = capture.clone();
+    let builder =
+        GcpLayer::builder().project_id("test-project").writer(move || writer.clone());
+    let (layer, _) = gcp_layer(builder).unwrap();
+    let counting = Counting::default();
+    let subscriber = tracing_subscriber::registry().with(layer).with(counting.clone());
+    tracing::subscriber::with_default(subscriber, || {
+        info!("first");
+        warn!("second");
+    });
+    let messages: Vec<_> = capture.take().iter().map(|e| e["message"].clone()).collect();
+    assert_eq!(messages, ["first", "second"]);
+    assert_eq!(*counting.0.lock().unwrap(), 2);
+}
+
+#[test]
+fn keeps_the_json_types_of_fields() {
+    let entries = entries(|| {
+        info!(
+            ok = true,
+            n = 5,
+            negative = -5,
+            ratio = 0.5,
+            big = u128::from(u64::MAX) + 1,
+            name = "alice",
+            debug = ?Some(1),
+            "typed"
+        )
+    });
+    assert_eq!(entries[0]["ok"], json!(true));
+    assert_eq!(entries[0]["n"], json!(5));
+    assert_eq!(entries[0]["negative"], json!(-5));
+    assert_eq!(entries[0]["ratio"], json!(0.5));
+    assert_eq!(entries[0]["big"], json!("18446744073709551616"));
+    assert_eq!(entries[0]["name"], json!("alice"));
+    assert_eq!(entries[0]["debug"], json!("Some(1)"));
+}
+
+#[test]
+fn logs_request_summaries_at_the_severity_of_their_status_class() {
+    let summary = |status| {
+        let http_request = HttpRequest {
+            request_method: Some("GET".into()),
+            request_url: Some("/orders".into()),
+            status: Some(status),
+            latency: Some("0.250s".into()),
+            ..HttpRequest::default()
+        };
+        entries(|| log_request_summary(http_request)).remove(0)
+    };
+    for (status, severity) in [
+        (200, "INFO"),
+        (302, "INFO"),
+        (404, "WARNING"),
+        (499, "WARNING"),
+        (500, "ERROR"),
+        (503, "ERROR"),
+    ] {
+        let entry = summary(status);
+        assert_eq!(entry["severity"], severity, "{status}");
+        assert_eq!(entry["message"], format!("GET /orders {status}"));
+        assert_eq!(entry["httpRequest"]["status"], status);
+        assert_eq!(entry["httpRequest"]["latency"], "0.250s");
+    }
+}
+
+#[test]
+fn writes_the_time_under_the_configured_key() {
+    let entries = capture(|b| b.time_key("timestamp"), || info!("renamed"));
+    assert_eq!(entries[0]["timestamp"], "2024-05-01T12:00:00.123Z");
+    assert_eq!(entries[0].get("time"), None);
+    assert_eq!(entries[0]["message"], "renamed");
+}
+
+#[test]
+fn raises_the_severity_of_events_to_the_span_floor() {
+    let entries = entries(|| {
+        info_span!("degraded", min_severity = "WARNING").in_scope(|| {
+            info!("raised");
+            error!("kept");
+            info_span!("inner").in_scope(|| info!("nested"));
+        });
+        info!("outside");
+    });
+    let severities: Vec<_> = entries.iter().map(|e| e["severity"].clone()).collect();
+    assert_eq!(severities, ["WARNING", "ERROR", "WARNING", "INFO"]);
+    assert_eq!(entries[0].get("min_severity"), None);
+}
+
+#[test]
+fn runs_in_the_trace_and_task_local_of_with_trace_id() {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let trace_ids = Arc::new(Mutex::new(Vec::new()));
+    let log = || {
+        let trace_ids = trace_ids.clone();
+        async move {
+            info!("in the trace");
+            trace_ids.lock().unwrap().push(gcp_logging_core::current_trace_id());
+        }
+    };
+    let entries = entries(|| {
+        runtime.block_on(with_tracing::with_trace_id(TRACE_ID, log()));
+        runtime.block_on(with_tracing::with_trace_id!(TRACE_ID, log()));
+    });
+    assert_eq!(entries.len(), 2);
+    for entry in &entries {
+        assert_eq!(
+            entry["logging.googleapis.com/trace"],
+            format!("projects/test-project/traces/{TRACE_ID}")
+        );
+    }
+    assert_eq!(
+        *trace_ids.lock().unwrap(),
+        [Some(TRACE_ID.into()), Some(TRACE_ID.into())]
+    );
+}
+
+#[test]
+fn serializes_identical_events_to_identical_bytes() {
+    let labels = HashMap::from([("a".into(), "1".into()), ("b".into(), "2".into())]);
+    let output = output(
+        |b| b.default_labels(labels),
+        || {
+            info_span!("request", trace_id = TRACE_ID, user = "alice").in_scope(|| {
+                for _ in 0..2 {
+                    info!(
+                        zeta = 1,
+                        alpha = true,
+                        mid = "m",
+                        label.tenant = "acme",
+                        "same"
+                    );
+                }
+            });
+        },
+    );
+    let lines: Vec<_> = output.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0], lines[1]);
+    // The GCP keys come first, then the fields in order of their names.
+    let severity = lines[0].find("\"severity\"").unwrap();
+    let alpha = lines[0].find("\"alpha\"").unwrap();
+    let zeta = lines[0].find("\"zeta\"").unwrap();
+    assert!(severity < alpha && alpha < zeta, "{}", lines[0]);
+}
+
+#[test]
+fn writes_numeric_severities() {
+    let entries = capture(
+        |b| b.numeric_severity(true),
+        || {
+            tracing::debug!("debug");
+            info!("info");
+            warn!("warn");
+            error!("error");
+        },
+    );
+    let severities: Vec<_> = entries.iter().map(|e| e["severity"].clone()).collect();
+    assert_eq!(severities, [json!(100), json!(200), json!(400), json!(500)]);
+}
+
+#[test]
+fn returns_the_trace_id_the_entries_get() {
+    let upper = TRACE_ID.to_uppercase();
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let mut ids = Vec::new();
+    let entries = entries(|| {
+        ids.push(current_trace_id());
+        info_span!("request", trace_id = upper.as_str()).in_scope(|| {
+            info!("upper");
+            ids.push(current_trace_id());
+        });
+        info_span!("invalid", trace_id = "not-a-trace").in_scope(|| {
+            info_span!("inner", trace_id = TRACE_ID).in_scope(|| {
+                info!("inner");
+                ids.push(current_trace_id());
+            })
+        });
+        ids.push(
+            runtime.block_on(gcp_logging_core::with_trace_id(upper.as_str(), async {
+                current_trace_id()
+            })),
+        );
+    });
+    let expected = Some(TRACE_ID.to_string());
+    assert_eq!(ids, [None, expected.clone(), expected.clone(), expected]);
+    for entry in &entries {
+        assert_eq!(
+            entry["logging.googleapis.com/trace"],
+            format!("projects/test-project/traces/{TRACE_ID}")
+        );
+    }
+}
+
+#[test]
+fn drops_the_fields_over_the_budget() {
+    let budget = FieldBudget::new(60).priorities(["user"]);
+    let entries = capture(
+        |b| b.field_budget(budget),
+        || info!(user = "alice", body = "x".repeat(100), n = 1, "budgeted"),
+    );
+    assert_eq!(entries[0]["user"], "alice");
+    assert_eq!(entries[0]["n"], 1);
+    assert_eq!(entries[0].get("body"), None);
+    assert_eq!(entries[0]["dropped_fields"], json!(["body"]));
+    assert_eq!(entries[0]["message"], "budgeted");
+}
+
+#[test]
+fn shares_one_generated_operation_id_between_the_events_of_operation_spans() {
+    let entries = entries(|| {
+        info_span!("job", operation = true).in_scope(|| {
+            info!("started");
+            info!("progressed");
+            info!("finished");
+        });
+        info_span!("job", operation = true).in_scope(|| info!("again"));
+    });
+    // Each operation also ends with an entry marking its last one.
+    let ids: Vec<_> = entries
+        .iter()
+        .map(|e| e["logging.googleapis.com/operation"]["id"].as_str().unwrap())
+        .collect();
+    assert_eq!(ids.len(), 6);
+    assert_eq!(ids[0].len(), 36, "{}", ids[0]);
+    assert_eq!(ids[0].as_bytes()[14], b'4', "{}", ids[0]);
+    assert!(ids[1..4].iter().all(|id| *id == ids[0]), "{ids:?}");
+    assert_ne!(ids[4], ids[0]);
+    assert_eq!(ids[5], ids[4]);
+    assert!(entries[0].get("operation").is_none());
+}
+
+#[test]
+fn separates_entries_with_the_record_delimiter() {
+    let output = output(
+        |b| b.record_delimiter(b'\0'),
+        || {
+            info!("first");
+            info!("second");
+        },
+    );
+    assert!(output.ends_with('\0'), "{output:?}");
+    assert!(!output.contains('\n'), "{output:?}");
+    let messages: Vec<_> = output
+        .split_terminator('\0')
+        .map(|record| serde_json::from_str::<Value>(record).unwrap()["message"].clone())
+        .collect();
+    assert_eq!(messages, ["first", "second"]);
+}
+
+#[test]
+fn drops_the_fields_skip_fields_picks() {
+    let entries = capture(
+        |b| {
+            b.skip_fields(|_, value| {
+                value.as_str().is_some_and(|value| value.starts_with("eyJ"))
+            })
+        },
+        || info!(token = "eyJhbGciOiJIUzI1NiJ9.e30.sig", user = "alice", "signed in"),
+    );
+    assert!(entries[0].get("token").is_none());
+    assert_eq!(entries[0]["user"], "alice");
+    assert_eq!(entries[0]["message"], "signed in");
+}
+
+/// Appends to a shared buffer one byte per write, as a writer that splits entries
+/// would.
+struct BytewiseWriter(Arc<Mutex<Vec<u8>>>);
+
+impl Write for BytewiseWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(&byte) = buf.first() else { return Ok(0) };
+        self.0.lock().unwrap().push(byte);
+        std::thread::yield_now();
+        Ok(1)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn writes_whole_lines_from_concurrent_threads() {
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let writer = LockedWriter::new(BytewiseWriter(output.clone()));
+    let (layer, _) = layer(|b| b.writer(BoxMakeWriter::new(move || writer.clone())));
+    let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+    let threads: Vec<_> = (0..8)
+        .map(|thread| {
+            let dispatch = dispatch.clone();
+            std::thread::spawn(move || {
+                tracing::dispatcher::with_default(&dispatch, || {
+                    for i in 0..50 {
+                        info!(thread, i, "concurrent");
+                    }
+                });
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+    let lines: Vec<_> = output.lines().collect();
+    assert_eq!(lines.len(), 8 * 50);
+    for line in lines {
+        let entry: Value = serde_json::from_str(line).unwrap();
+        assert_eq!(entry["message"], "concurrent");
+    }
+}
+
+#[test]
+fn routes_label_fields_into_the_labels() {
+    let entries = capture(
+        |b| b.with_span_timing(true),
+        || {
+            info!(label.env = "prod", count = 2, "counted");
+            info_span!("batch", label.env = "staging").in_scope(|| {});
+        },
+    );
+    assert_eq!(entries[0]["logging.googleapis.com/labels"]["env"], "prod");
+    assert_eq!(entries[0]["count"], 2);
+    assert!(entries[0].get("label.env").is_none());
+    assert!(entries[0]["logging.googleapis.com/labels"].get("count").is_none());
+    assert_eq!(entries[1]["span"], "batch");
+    assert_eq!(entries[1]["logging.googleapis.com/labels"]["env"], "staging");
+}
+
+#[test]
+fn writes_plain_keys_in_the_generic_mode() {
+    let entries = capture(
+        |b| b.entry_keys(EntryKeys::Generic).with_source_location(true),
+        || {
+            info_span!("request", trace_id = TRACE_ID)
+                .in_scope(|| info!(label.env = "prod", count = 2, "generic"));
+        },
+    );
+    let entry = entries[0].as_object().unwrap();
+    assert!(
+        entry.keys().all(|key| !key.starts_with("logging.googleapis.com/")),
+        "{entry:?}"
+    );
+    assert_eq!(entry["trace"], format!("projects/test-project/traces/{TRACE_ID}"));
+    assert_eq!(entry["span_id"].as_str().unwrap().len(), 16);
+    assert_eq!(entry["labels"], json!({ "env": "prod" }));
+    assert_eq!(entry["source_location"]["file"], file!());
+    assert_eq!(entry["count"], 2);
+    assert_eq!(entry["message"], "generic");
+}
+
+#[test]
+fn keeps_the_renamed_time_and_message_of_dropped_entries() {
+    let body = "x".repeat(512);
+    let entries = capture(
+        |b| {
+            b.time_key("timestamp")
+                .message_key("msg")
+                .entry_size_limit(256, OversizedEntry::Drop)
+        },
+        || info!(body = body.as_str(), "request received"),
+    );
+    let entry = &entries[0];
+    assert_eq!(entry["severity"], "WARNING");
+    assert_eq!(entry["timestamp"], "2024-05-01T12:00:00.123Z");
+    assert!(entry["msg"].as_str().unwrap().starts_with("dropped a "), "{entry}");
+    assert!(entry.get("message").is_none());
+    assert!(entry.get("time").is_none());
+    assert!(entry.get("body").is_none());
+}
+
+#[test]
+fn writes_the_numeric_severity_of_dropped_entries() {
+    let body = "x".repeat(512);
+    let entries = capture(
+        |b| b.numeric_severity(true).entry_size_limit(256, OversizedEntry::Drop),
+        || info!(body = body.as_str(), "request received"),
+    );
+    assert_eq!(entries[0]["severity"], 400);
+    assert!(entries[0]["message"].as_str().unwrap().starts_with("dropped a "));
+}
@@ -0,0 +1,60 @@
+//! Requests going through the `TraceContextLayer` middleware of `gcp-logging-core`.
+
+use gcp_logging_core::middleware::TraceContextLayer;
+use gcp_logging_core::{CaptureWriter, current_trace_id};
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+use tracing::info;
+use tracing_subscriber::prelude::*;
+use with_tracing::GcpLayer;
+
+/// A handler logging from its future, as an `async fn` handler would.
+struct Handler;
+
+impl Service<http::Request<()>> for Handler {
+    type Response = Option<String>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Option<String>, Infallible>>>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: http::Request<()>) -> Self::Future {
+        Box::pin(async {
+            info!("handled");
+            Ok(current_trace_id())
+        })
+    }
+}
+
+#[tokio::test]
+async fn runs_requests_in_the_trace_of_their_header() {
+    let capture = CaptureWriter::new();
+    let writer = capture.clone();
+    let layer = GcpLayer::builder()
+        .project_id("test-project")
+        .writer(move || writer.clone())
+        .build()
+        .unwrap();
+    let _guard =
+        tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+
+    let request = http::Request::builder()
+        .header("X-Cloud-Trace-Context", "4bf92f3577b34da6a3ce929d0e0e4736/1234;o=0")
+        .body(())
+        .unwrap();
+    let mut service = TraceContextLayer::default().layer(Handler);
+    let task_local_trace_id = service.call(request).await.unwrap();
+
+    let entries = capture.take();
+    assert_eq!(
+        entries[0]["logging.googleapis.com/trace"],
+        "projects/test-project/traces/4bf92f3577b34da6a3ce929d0e0e4736"
+    );
+    assert_eq!(entries[0]["logging.googleapis.com/spanId"], "00000000000004d2");
+    assert_eq!(entries[0]["logging.googleapis.com/trace_sampled"], false);
+    assert_eq!(task_local_trace_id.as_deref(), Some("4bf92f3577b34da6a3ce929d0e0e4736"));
+}
@@ -0,0 +1,100 @@
+//! The traces `GcpLayer` reads from `tracing_opentelemetry` spans.
+#![cfg(feature = "opentelemetry")]
+
+use gcp_logging_core::{CaptureWriter, FixedClock};
+use opentelemetry::KeyValue;
+use opentelemetry::baggage::BaggageExt;
+use opentelemetry::trace::{TraceContextExt, TracerProvider};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use serde_json::Value;
+use tracing::{info, info_span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
+use with_tracing::{GcpLayer, GcpLayerBuilder};
+
+/// The entries written while `log` runs under both an OpenTelemetry layer and a
+/// `GcpLayer` reading it, configured by `configure`.
+fn capture(
+    configure: impl FnOnce(GcpLayerBuilder<BoxMakeWriter>) -> GcpLayerBuilder<BoxMakeWriter>,
+    log: impl FnOnce(),
+) -> Vec<Value> {
+    let capture = CaptureWriter::new();
+    let writer = capture.clone();
+    let builder = GcpLayer::builder()
+        .project_id("test-project")
+        .clock(FixedClock("2024-05-01T12:00:00Z".parse().unwrap()))
+        .with_opentelemetry(true)
+        .writer(BoxMakeWriter::new(move || writer.clone()));
+    let layer = configure(builder).build().unwrap();
+    let tracer = SdkTracerProvider::builder().build().tracer("test");
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .with(layer);
+    tracing::subscriber::with_default(subscriber, log);
+    capture.take()
+}
+
+/// The entries written while `log` runs with a default `GcpLayer`.
+fn entries(log: impl FnOnce()) -> Vec<Value> {
+    capture(|b| b, log)
+}
+
+#[test]
+fn writes_the_trace_of_opentelemetry_spans() {
+    let mut ids = None;
+    let entries = entries(|| {
+        let span = info_span!("request");
+        let context = span.context();
+        let span_context = context.span().span_context().clone();
+        ids = Some((
+            span_context.trace_id().to_string(),
+            span_context.span_id().to_string(),
+        ));
+        span.in_scope(|| info!("traced"));
+    });
+    let (trace_id, span_id) = ids.unwrap();
+    assert_eq!(
+        entries[0]["logging.googleapis.com/trace"],
+        format!("projects/test-project/traces/{trace_id}")
+    );
+    assert_eq!(entries[0]["logging.googleapis.com/spanId"], span_id);
+    assert_eq!(entries[0]["logging.googleapis.com/trace_sampled"], true);
+}
+
+#[test]
+fn prefers_the_trace_id_field_over_opentelemetry() {
+    let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+    let entries = entries(|| {
+        info_span!("request", trace_id).in_scope(|| info!("traced"));
+    });
+    assert_eq!(
+        entries[0]["logging.googleapis.com/trace"],
+        format!("projects/test-project/traces/{trace_id}")
+    );
+}
+
+#[test]
+fn writes_the_baggage_as_labels() {
+    let log = || {
+        let cx = opentelemetry::Context::current_with_baggage([
+            KeyValue::new("tenant", "acme"),
+            KeyValue::new("flag", "beta"),
+        ]);
+        {
+            let _cx = cx.clone().attach();
+            info!("current context");
+        }
+        let span = info_span!("request");
+        span.set_parent(cx);
+        span.in_scope(|| info!("parent context"));
+    };
+    let entries = capture(|b| b.with_baggage_labels(true), log);
+    let labels = &entries[0]["logging.googleapis.com/labels"];
+    assert_eq!(labels["tenant"], "acme");
+    assert_eq!(labels["flag"], "beta");
+    assert_eq!(entries[1]["logging.googleapis.com/labels"]["flag"], "beta");
+
+    let without = capture(|b| b, log);
+    assert!(without[0].get("logging.googleapis.com/labels").is_none());
+}